@@ -1,5 +1,278 @@
+use std::{env, fs, path::{Path, PathBuf}, process::Command};
 
-// The main point here is to specify a custom linkerscript
+// The main point here is to specify a custom linkerscript. It also
+// builds the symbol table for backtraces, see symbols below.
 fn main() {
-    println!("cargo:rustc-link-arg=-T./kernel/kernel.ld")
+    println!("cargo:rerun-if-changed=kernel.ld");
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    symbols(&out_dir);
+
+    if env::var_os("CARGO_FEATURE_HAL_VIRT_BARE").is_none() {
+        println!("cargo:rustc-link-arg=-T./kernel/kernel.ld");
+        return;
+    }
+
+    // Without firmware qemu jumps to the bottom of memory, so that's
+    // where we have to be, and we start in M mode
+    let script = fs::read_to_string("kernel.ld").expect("Could not read kernel.ld");
+    const DEFAULT_BASE: &str = "KERNEL_BASE = 0xA0000000;";
+    assert!(script.contains(DEFAULT_BASE), "kernel.ld no longer sets KERNEL_BASE the way build.rs expects");
+    let script = script.replace(DEFAULT_BASE, "KERNEL_BASE = 0x80000000;");
+
+    let out = out_dir.join("kernel.ld");
+    fs::write(&out, script).expect("Could not write the bare linkerscript");
+    println!("cargo:rustc-link-arg=-T{}", out.display());
+    println!("cargo:rustc-link-arg=--entry=_mentry");
+}
+
+// -------------------------------------------------------------------
+// Symbols for backtraces
+//
+// We can't read the .symtab of the kernel we are building, as it
+// isn't linked yet. So we build it once more on the side, with an
+// empty table, and take the symbols from that. The table is linked
+// after .text and only found through linker symbols, so its size
+// changes no code and the addresses carry over. The kernel checks
+// that they did (see backtrace.rs), and does without names if not.
+//
+// That doubles the build time, so it's skipped for clippy, and for
+// anything else if KERNEL_NO_SYMBOLS is set (handy with cargo check).
+
+/// Set in the side build, which just gets the empty table
+const SYMBOLS_PASS_ENV: &str = "KERNEL_SYMBOLS_PASS";
+
+/// Set to skip the side build, leaving backtraces without names
+const NO_SYMBOLS_ENV: &str = "KERNEL_NO_SYMBOLS";
+
+const TABLE_MAGIC: &[u8; 8] = b"KSYMTAB\0";
+
+fn symbols(out_dir: &Path) {
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    println!("cargo:rerun-if-env-changed={}", SYMBOLS_PASS_ENV);
+    println!("cargo:rerun-if-env-changed={}", NO_SYMBOLS_ENV);
+    let table_path = out_dir.join("ksyms.bin");
+
+    // clippy goes through the workspace wrapper, and doesn't link
+    let skip = env::var_os(SYMBOLS_PASS_ENV).is_some() ||
+        env::var_os(NO_SYMBOLS_ENV).is_some() ||
+        env::var_os("RUSTC_WORKSPACE_WRAPPER").is_some();
+    let table = if skip {
+        table_bytes(0, 0, &[])
+    } else {
+        match side_build(out_dir) {
+            Ok(elf) => match read_symbols(&elf) {
+                Some((anchor, text_end, syms)) => table_bytes(anchor, text_end, &syms),
+                None => {
+                    println!("cargo:warning=No usable .symtab in {}, backtraces won't have names", elf.display());
+                    table_bytes(0, 0, &[])
+                },
+            },
+            Err(e) => {
+                // most likely the kernel doesn't compile, which the
+                // real build is about to tell you about
+                println!("cargo:warning=Symbol table build failed ({}), backtraces won't have names", e);
+                table_bytes(0, 0, &[])
+            },
+        }
+    };
+    fs::write(table_path, table).expect("Could not write the symbol table");
+}
+
+/// Build this kernel again, same features and profile, into its own
+/// target dir. Gives the path of the ELF.
+fn side_build(out_dir: &Path) -> Result<PathBuf, String> {
+    let target = env::var("TARGET").unwrap();
+    let profile = env::var("PROFILE").unwrap();
+    let target_dir = out_dir.join("symbols");
+
+    let features: Vec<String> = env::vars()
+        .filter_map(|(k, _)| k.strip_prefix("CARGO_FEATURE_").map(|f| f.to_lowercase().replace('_', "-")))
+        .collect();
+
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+    let mut cmd = Command::new(cargo);
+    cmd.arg("build")
+        .arg("--offline")
+        .arg("--manifest-path").arg(Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join("Cargo.toml"))
+        .arg("--target").arg(&target)
+        .arg("--target-dir").arg(&target_dir)
+        .arg("--no-default-features")
+        .arg("--features").arg(features.join(","));
+    if profile == "release" {
+        cmd.arg("--release");
+    }
+    cmd.env(SYMBOLS_PASS_ENV, "1");
+
+    let output = cmd.output().map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!("exit {}", output.status));
+    }
+    Ok(target_dir.join(&target).join(&profile).join("kernel"))
+}
+
+struct Symbol {
+    addr: u64,
+    size: u64,
+    name: String,
+}
+
+fn u16_at(b: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(b[at..at + 2].try_into().unwrap())
+}
+
+fn u32_at(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(b[at..at + 4].try_into().unwrap())
+}
+
+fn u64_at(b: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(b[at..at + 8].try_into().unwrap())
+}
+
+fn c_str(b: &[u8], at: usize) -> &str {
+    let end = b[at..].iter().position(|&c| c == 0).map_or(b.len(), |n| at + n);
+    std::str::from_utf8(&b[at..end]).unwrap_or("")
+}
+
+const SHT_SYMTAB: u32 = 2;
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+
+/// The code symbols of a little endian ELF64, sorted by address,
+/// along with the addresses of main and _text_end to check against
+fn read_symbols(path: &Path) -> Option<(u64, u64, Vec<Symbol>)> {
+    let elf = fs::read(path).ok()?;
+    if elf.get(..6)? != b"\x7fELF\x02\x01" {
+        return None;
+    }
+    let shoff = u64_at(&elf, 0x28) as usize;
+    let shentsize = u16_at(&elf, 0x3a) as usize;
+    let shnum = u16_at(&elf, 0x3c) as usize;
+    let section = |i: usize| shoff + i * shentsize;
+
+    let symtab = (0..shnum).map(section).find(|&s| u32_at(&elf, s + 4) == SHT_SYMTAB)?;
+    let strtab = section(u32_at(&elf, symtab + 40) as usize);
+    let strings = &elf[u64_at(&elf, strtab + 24) as usize..][..u64_at(&elf, strtab + 32) as usize];
+    let entries = &elf[u64_at(&elf, symtab + 24) as usize..][..u64_at(&elf, symtab + 32) as usize];
+
+    let (mut anchor, mut text_start, mut text_end) = (None, None, None);
+    let mut syms = Vec::new();
+    for sym in entries.chunks_exact(24) {
+        let name = c_str(strings, u32_at(sym, 0) as usize);
+        let kind = sym[4] & 0xf;
+        let shndx = u16_at(sym, 6);
+        let addr = u64_at(sym, 8);
+        let size = u64_at(sym, 16);
+        match name {
+            "main" => anchor = Some(addr),
+            "_text_start" => text_start = Some(addr),
+            "_text_end" => text_end = Some(addr),
+            _ => {},
+        }
+        // asm labels are NOTYPE, skip the local and mapping ones
+        let code = kind == STT_FUNC ||
+            (kind == STT_NOTYPE && !name.starts_with(".L") && !name.starts_with('$'));
+        if code && shndx != SHN_UNDEF && shndx != SHN_ABS && !name.is_empty() {
+            syms.push(Symbol { addr, size, name: demangle(name) });
+        }
+    }
+    let (text_start, text_end) = (text_start?, text_end?);
+    syms.retain(|s| (text_start..text_end).contains(&s.addr));
+    syms.sort_by_key(|s| s.addr);
+    syms.dedup_by_key(|s| s.addr);
+    Some((anchor?, text_end, syms))
+}
+
+/// Undo rust's legacy mangling, dropping the hash. Anything else
+/// comes back as is.
+fn demangle(name: &str) -> String {
+    let mut rest = match name.strip_prefix("_ZN") {
+        Some(r) => r,
+        None => return name.to_string(),
+    };
+    let mut parts = Vec::new();
+    while !rest.starts_with('E') {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let len: usize = match rest[..digits].parse() {
+            Ok(len) if rest.len() >= digits + len => len,
+            _ => return name.to_string(),
+        };
+        parts.push(&rest[digits..digits + len]);
+        rest = &rest[digits + len..];
+    }
+    if let Some(last) = parts.last() {
+        if last.len() == 17 && last.starts_with('h') && last[1..].bytes().all(|c| c.is_ascii_hexdigit()) {
+            parts.pop();
+        }
+    }
+    let parts: Vec<String> = parts.iter().map(|p| unescape(p)).collect();
+    parts.join("::")
+}
+
+fn unescape(part: &str) -> String {
+    let part = part.strip_prefix("_$").map_or(part.to_string(), |p| format!("${}", p));
+    let mut out = String::new();
+    let mut rest = part.as_str();
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix("..") {
+            out.push_str("::");
+            rest = r;
+            continue;
+        }
+        if rest.starts_with('$') {
+            if let Some(end) = rest[1..].find('$') {
+                let code = &rest[1..end + 1];
+                let ch = match code {
+                    "SP" => Some('@'),
+                    "BP" => Some('*'),
+                    "RF" => Some('&'),
+                    "LT" => Some('<'),
+                    "GT" => Some('>'),
+                    "LP" => Some('('),
+                    "RP" => Some(')'),
+                    "C" => Some(','),
+                    _ => code.strip_prefix('u')
+                        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                        .and_then(char::from_u32),
+                };
+                if let Some(ch) = ch {
+                    out.push(ch);
+                    rest = &rest[end + 2..];
+                    continue;
+                }
+            }
+        }
+        let ch = rest.chars().next().unwrap();
+        out.push(ch);
+        rest = &rest[ch.len_utf8()..];
+    }
+    out
+}
+
+/// The layout backtrace.rs reads, all little endian:
+///
+/// magic, main, _text_end, count, strings length: u64 each
+/// count entries of address: u64, size: u64, name offset: u32, name length: u32
+/// the names
+fn table_bytes(anchor: u64, text_end: u64, syms: &[Symbol]) -> Vec<u8> {
+    let mut strings = Vec::new();
+    let mut entries = Vec::new();
+    for sym in syms {
+        entries.extend_from_slice(&sym.addr.to_le_bytes());
+        entries.extend_from_slice(&sym.size.to_le_bytes());
+        entries.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+        entries.extend_from_slice(&(sym.name.len() as u32).to_le_bytes());
+        strings.extend_from_slice(sym.name.as_bytes());
+    }
+    let mut out = Vec::new();
+    out.extend_from_slice(TABLE_MAGIC);
+    out.extend_from_slice(&anchor.to_le_bytes());
+    out.extend_from_slice(&text_end.to_le_bytes());
+    out.extend_from_slice(&(syms.len() as u64).to_le_bytes());
+    out.extend_from_slice(&(strings.len() as u64).to_le_bytes());
+    out.extend_from_slice(&entries);
+    out.extend_from_slice(&strings);
+    out
 }
@@ -0,0 +1,133 @@
+//! Printing backtraces, with names from the symbol table build.rs
+//! embeds. The HAL does the actual stack walking.
+//!
+//! This runs in the panic handler, so nothing here allocates, and
+//! output goes through panic_println!, which doesn't wait on whoever
+//! had the console.
+
+use core::ptr::addr_of;
+
+use crate::hal::*;
+
+/// Deepest backtrace we print
+pub const MAX_FRAMES: usize = 32;
+
+const MAGIC: &[u8; 8] = b"KSYMTAB\0";
+const HEADER_SIZE: usize = 5 * 8;
+const ENTRY_SIZE: usize = 24;
+
+#[used]
+#[link_section = ".ksyms"]
+static KSYMS: [u8; include_bytes!(concat!(env!("OUT_DIR"), "/ksyms.bin")).len()] =
+    *include_bytes!(concat!(env!("OUT_DIR"), "/ksyms.bin"));
+
+extern "C" {
+    static _ksyms_start: u8;
+    static _ksyms_end: u8;
+}
+
+/// The table, found through the linker rather than KSYMS so that its
+/// size isn't baked into any code. See build.rs for why.
+fn table() -> &'static [u8] {
+    unsafe {
+        let start = addr_of!(_ksyms_start);
+        let len = addr_of!(_ksyms_end) as usize - start as usize;
+        core::slice::from_raw_parts(start, len)
+    }
+}
+
+fn u64_at(b: &[u8], at: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&b[at..at + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn u32_at(b: &[u8], at: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&b[at..at + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+/// A checked view of the symbol table
+struct Symbols {
+    entries: &'static [u8],
+    strings: &'static [u8],
+}
+
+impl Symbols {
+    /// None if there is no table, or it came from a build with
+    /// different code
+    fn get() -> Option<Self> {
+        let table = table();
+        if table.len() < HEADER_SIZE || &table[..8] != MAGIC {
+            return None;
+        }
+        let anchor = u64_at(table, 8) as usize;
+        let text_end = u64_at(table, 16) as usize;
+        if anchor != crate::main as extern "C" fn() -> ! as usize || text_end != HAL::text_end() as usize {
+            return None;
+        }
+        let count = u64_at(table, 24) as usize;
+        let strings_len = u64_at(table, 32) as usize;
+        let entries = table.get(HEADER_SIZE..HEADER_SIZE + count * ENTRY_SIZE)?;
+        let strings = table.get(HEADER_SIZE + entries.len()..)?.get(..strings_len)?;
+        Some(Self { entries, strings })
+    }
+
+    fn entry(&self, i: usize) -> (usize, usize, &'static str) {
+        let e = &self.entries[i * ENTRY_SIZE..];
+        let addr = u64_at(e, 0) as usize;
+        let size = u64_at(e, 8) as usize;
+        let off = u32_at(e, 16) as usize;
+        let len = u32_at(e, 20) as usize;
+        let name = self.strings.get(off..off + len)
+            .and_then(|n| core::str::from_utf8(n).ok())
+            .unwrap_or("?");
+        (addr, size, name)
+    }
+
+    /// The symbol addr is in, and how far into it
+    fn lookup(&self, addr: usize) -> Option<(&'static str, usize)> {
+        let count = self.entries.len() / ENTRY_SIZE;
+        // last entry at or before addr, they're sorted
+        let (mut lo, mut hi) = (0, count);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.entry(mid).0 <= addr {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        let (start, size, name) = self.entry(lo.checked_sub(1)?);
+        // asm labels have no size, so take them as running up to the
+        // next symbol
+        if size != 0 && addr >= start + size {
+            return None;
+        }
+        Some((name, addr - start))
+    }
+}
+
+/// Print a backtrace of addresses, innermost first
+pub fn print(frames: &[usize]) {
+    let symbols = Symbols::get();
+    if symbols.is_none() {
+        panic_println!("backtrace (no symbols, they don't match this build):");
+    } else {
+        panic_println!("backtrace:");
+    }
+    for (i, &addr) in frames.iter().enumerate() {
+        match symbols.as_ref().and_then(|s| s.lookup(addr)) {
+            Some((name, offset)) => panic_println!("  {:2}: 0x{:x} {}+0x{:x}", i, addr, name, offset),
+            None => panic_println!("  {:2}: 0x{:x}", i, addr),
+        }
+    }
+}
+
+/// Print a backtrace of whoever called this
+pub fn print_here() {
+    let mut frames = [0; MAX_FRAMES];
+    let n = HAL::backtrace(&mut frames);
+    print(&frames[..n]);
+}
@@ -1,12 +1,22 @@
 use alloc::vec::Vec;
+use core::time::Duration;
+use core::panic::PanicInfo;
 /// This module should contain the details of the hardware abstraction
 /// layer
 
 use bitflags::bitflags;
 
 use crate::vm::{palloc::Page, PhysPageExtent};
+use crate::hartlocal::HartLocal;
+use crate::process::Process;
 
-#[cfg(feature = "hal-virt")]
+pub mod fdt;
+
+#[cfg(all(feature = "hal-virt", feature = "hal-sifive-u"))]
+compile_error!("Only one hal- backing can be enabled. hal-virt is on by default, so use --no-default-features with the others.");
+
+// sifive_u is close enough to virt to share its backing
+#[cfg(any(feature = "hal-virt", feature = "hal-sifive-u"))]
 pub mod virt;
 
 pub struct HAL {}
@@ -19,44 +29,128 @@ pub trait HALSerial {
     // Unless otherwise stated, these functions apply to the primary
     // serial port.
     //
-    // TODO consider further buffering beyond hardware in the kernel's
-    // view of the serial port. If so, add flush.
+    // Input and output are both buffered in the kernel. Output is
+    // line buffered, so anything that doesn't end in a newline needs
+    // a serial_flush to be sure it has gone out.
+    //
+    // None of these panic on a console failure, as they are used from
+    // the panic path. Output that fails is dropped.
 
-    /// Call a single time before any serial operations
-    fn serial_setup();
+    /// Call a single time before any serial operations. If this
+    /// fails, everything else fails with Unavailable.
+    fn serial_setup() -> Result<(), HALSerialError>;
 
     /// Write a char out to serial. If not an ascii char, then this
     /// should send multiple bytes.
-    fn serial_put_char(c: char);
+    fn serial_put_char(c: char) -> Result<(), HALSerialError>;
 
-    /// This is a spin-blocking read from the primary serial port.
-    fn serial_read_byte() -> u8;
+    /// This is a blocking read from the primary serial port. Where
+    /// the hardware can interrupt on input, this waits for that rather
+    /// than spinning. Don't call it holding an IrqMutex.
+    fn serial_read_byte() -> Result<u8, HALSerialError>;
 
     /// This is a convience function for non-streaming prints. It is
     /// preffered when possible.
-    fn serial_put_string(s: &str);
+    fn serial_put_string(s: &str) -> Result<(), HALSerialError>;
 
     /// This is a convience wrapper for reading a known number of
     /// bytes. It is prefered when possible.
-    fn serial_read_bytes(buf: &mut [u8], num: u32);
+    fn serial_read_bytes(buf: &mut [u8], num: u32) -> Result<(), HALSerialError>;
+
+    /// A byte of input if there is one, without waiting
+    fn serial_try_read_byte() -> Result<Option<u8>, HALSerialError>;
+
+    /// Read whatever input is waiting into buf, without waiting for
+    /// more. Returns how many bytes were read.
+    fn serial_read_nonblocking(buf: &mut [u8]) -> Result<usize, HALSerialError>;
+
+    /// Read a line of input into buf, echoing it and handling
+    /// backspace. Returns the length, not counting the line ending,
+    /// which isn't stored.
+    fn serial_read_line(buf: &mut [u8]) -> Result<usize, HALSerialError>;
+
+    /// Whether there is input waiting to be read
+    fn serial_input_ready() -> Result<bool, HALSerialError>;
+
+    /// Have notify called whenever new input arrives, possibly from
+    /// an interrupt handler, so it must be quick and must not print.
+    /// This is how waiting for input can avoid polling. Replaces any
+    /// earlier notify.
+    fn serial_on_input(notify: fn());
+
+    /// Pick up any input the hardware has. Input may only be noticed
+    /// here if interrupts are off or the hardware can't interrupt, see
+    /// HALIPI::ipi_poll.
+    fn serial_poll() -> Result<(), HALSerialError>;
+
+    /// Push out any buffered output
+    fn serial_flush() -> Result<(), HALSerialError>;
+
+    /// serial_put_string for the panic handler. Doesn't wait on
+    /// anyone who had the console when we panicked, they aren't
+    /// coming back for it.
+    fn serial_panic_put_string(s: &str) -> Result<(), HALSerialError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HALSerialError {
+    /// There is no console, or setting it up failed
+    Unavailable,
+    /// The console failed partway through
+    Io,
+    /// Any other failure reported by the backing
+    Other,
 }
 
 pub trait HALTimer {
     /// Call once before any timer use
     fn timer_setup();
 
-    /// Set a timer to go off a single time.
-    ///
-    /// TODO how to set a meaning of a tick that is reasonable across
-    /// hardwares. RISC-V uses mtime, which is not even fully defined
-    /// there. See priv spec.
-    ///
-    /// The natural thing is to do realtime, but I'm not sure how to
-    /// convert mtime to realtime
+    /// Set a timer to go off a single time, `ticks` from now. This
+    /// replaces any timer that has not gone off yet. A tick is one
+    /// unit of `now`, see `timebase_frequency` for real time.
     fn timer_set(ticks: u64);
 
-    // TODO timer clear? timers are one time only, so ideally don't
-    // start ones that you don't wnat to happen
+    /// Cancel the pending timer on this CPU, if there is one.
+    fn timer_clear();
+
+    /// The current time in ticks. This is monotonic, counts up from
+    /// some point at or before boot, and is the same on all CPUs.
+    fn now() -> u64;
+
+    /// How many ticks of `now` there are in a second.
+    fn timebase_frequency() -> u64;
+
+    /// Wall clock time as time since the unix epoch, read straight
+    /// from the hardware. None if there is no clock to read. This is
+    /// slow, see crate::time for something to use often.
+    fn wall_clock() -> Option<Duration>;
+
+    // The rest are conversions to real time on top of the above. You
+    // probably don't want to override these.
+
+    /// Convert a number of ticks to nanoseconds, rounding down.
+    fn ticks_to_nanos(ticks: u64) -> u64 {
+        ((ticks as u128 * 1_000_000_000) / Self::timebase_frequency() as u128) as u64
+    }
+
+    /// Convert nanoseconds to a number of ticks, rounding up so that
+    /// a timer never goes off early.
+    fn nanos_to_ticks(nanos: u64) -> u64 {
+        let freq = Self::timebase_frequency() as u128;
+        (nanos as u128 * freq).div_ceil(1_000_000_000) as u64
+    }
+
+    /// Time since `now` started counting, usually boot.
+    fn uptime() -> Duration {
+        Duration::from_nanos(Self::ticks_to_nanos(Self::now()))
+    }
+
+    /// Set a timer to go off a single time, after a real amount of
+    /// time. Same semantics as `timer_set`.
+    fn timer_set_after(after: Duration) {
+        Self::timer_set(Self::nanos_to_ticks(after.as_nanos() as u64));
+    }
 }
 
 // -------------------------------------------------------------------
@@ -80,13 +174,14 @@ pub enum HALVMError {
     MisalignedAddress,
     FailedAllocation,
     UnsupportedFlags(PageMapFlags),      // Returns set of unsupported flags
+    NotMapped,
     // TODO others?
 }
 
 bitflags! {
 /// Things that you can request of a page mapping. Not all may be
 /// valid for all hardware. See associated error.
-    #[derive(PartialEq, Eq)]
+    #[derive(PartialEq, Eq, Clone, Copy, Debug)]
     pub struct PageMapFlags: u32 {
         const Read     = 0x00_00_00_01;
         const Write    = 0x00_00_00_02;
@@ -99,6 +194,39 @@ bitflags! {
     }
 }
 
+/// Tags an address space in the TLB, so switching to it doesn't need
+/// a flush. Starts out unassigned, and the HAL hands out a real one
+/// (and may later replace it) in pgtbl_prepare_switch. Keep one with
+/// each page table that gets switched to.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Asid(usize);
+
+impl Asid {
+    pub const fn new() -> Self {
+        Asid(0)
+    }
+}
+
+/// One present mapping in a page table, see HALVM::pgtbl_mappings
+#[derive(Clone, Copy, Debug)]
+pub struct PgtblMapping {
+    pub virt: VirtAddress,
+    pub phys: PhysAddress,
+    /// Bytes mapped, which can be more than a page
+    pub nbytes: usize,
+    pub flags: PageMapFlags,
+}
+
+/// What pgtbl_deep_copy does with the pages mapped by the source
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PgtblCopyMode {
+    /// Both tables map the same physical pages
+    ShareLeaves,
+    /// The copy gets its own copies of user pages. Kernel mappings
+    /// are still shared.
+    DuplicateLeaves,
+}
+
 pub const PAGE_SIZE: usize = 4096;
 pub const PAGE_OFFSET: usize = 12;
 
@@ -112,7 +240,11 @@ pub trait HALVM {
     /// implementer of HALVM's responsibility to ensure that there are
     /// not overlaps with the generic kernel mappings. Hardware
     /// specific mappings will overwrite general mappings
-    fn kernel_reserved_areas() -> Vec<(PhysPageExtent, PageMapFlags)>;
+    ///
+    /// Areas are (start address, number of pages, flags). They are
+    /// device memory and the like, which the kernel maps but doesn't
+    /// own, so nothing here is ever handed to the page allocator.
+    fn kernel_reserved_areas() -> Vec<(usize, usize, PageMapFlags)>;
 
     /// Called on the populated kernel pagetable. May be empty for
     /// some platforms
@@ -125,8 +257,14 @@ pub trait HALVM {
     /// following functions.
     fn pgtbl_new_empty() -> Result<PageTable, HALVMError>;
 
-    /// Make a full copy of the supplied page table.
-    fn pgtbl_deep_copy(src: PageTable, dest: PageTable) -> Result<(), HALVMError>;
+    /// Make a full copy of the supplied page table into dest, which
+    /// should be empty (from pgtbl_new_empty). mode picks whether the
+    /// copy shares the mapped pages or gets its own. Pages copied for
+    /// DuplicateLeaves are returned, and are the caller's to keep for
+    /// as long as dest maps them, as pgtbl_free doesn't free
+    /// them. On error any copies are already freed, and dest may
+    /// hold a partial copy, which should be freed.
+    fn pgtbl_deep_copy(src: PageTable, dest: PageTable, mode: PgtblCopyMode) -> Result<Vec<PhysPageExtent>, HALVMError>;
 
     /// Insert the given page into the given table at the given
     /// location. Flags should be specified here, although it's
@@ -139,7 +277,31 @@ pub trait HALVM {
         flags: PageMapFlags
     ) -> Result<(), HALVMError>;
 
-    /// Remove the mapping at the address in the given page table
+    /// Find what virt maps to in the given table. Gives the physical
+    /// address (with virt's offset into the page) and the flags of
+    /// the mapping, or None if virt isn't mapped.
+    fn pgtbl_lookup(pgtbl: &PageTable, virt: VirtAddress) -> Option<(PhysAddress, PageMapFlags)>;
+
+    /// Change the flags of everything mapped in the range, keeping
+    /// where it points. Fails with NotMapped, changing nothing, if any
+    /// page in the range isn't mapped.
+    fn pgtbl_protect_range(
+        pgtbl: PageTable,
+        virt: VirtAddress,
+        nbytes: usize,
+        flags: PageMapFlags
+    ) -> Result<(), HALVMError>;
+
+    /// Iterator over the present mappings of a table, see
+    /// pgtbl_mappings
+    type PgtblIter: Iterator<Item = PgtblMapping>;
+
+    /// Every present mapping in the table, in address order. The
+    /// table must not change while this is in use.
+    fn pgtbl_mappings(pgtbl: &PageTable) -> Self::PgtblIter;
+
+    /// Remove the mapping at the address in the given page table.
+    /// Intermediate levels that end up empty are freed.
     fn pgtbl_remove_range(pgtbl: PageTable, virt: VirtAddress, nbytes: usize) -> Result<(), HALVMError>;
 
     /// Change your page table. Only safe in the next instruction
@@ -148,6 +310,15 @@ pub trait HALVM {
     /// appropriate permissions in destination page table.
     fn pgtbl_swap(pgtbl: &PageTable);
 
+    /// Get ready to switch this CPU to pgtbl, tagged with asid, and
+    /// return what the switch code should install (satp on riscv).
+    /// asid may be replaced. Any TLB maintenance this CPU owes is done
+    /// here, so the switch itself doesn't need a full flush.
+    fn pgtbl_prepare_switch(pgtbl: &PageTable, asid: &mut Asid) -> usize;
+
+    /// Free the page table and every intermediate level under it. The
+    /// pages it maps are left alone, they belong to whoever mapped
+    /// them.
     // TODO make this a drop trait. Will that ruin inheritence?
     fn pgtbl_free(pgtbl: PageTable);
 }
@@ -170,10 +341,75 @@ pub trait HALVM {
 /// can be implemented with addributes that check for the cargo
 /// features for the desired HAl.
 pub trait HALIntExc {
+    /// The saved state of whatever a trap interrupted, as handed to
+    /// the trap handlers. Changes to it take effect when the trap
+    /// returns.
+    type TrapFrame: HALTrapFrame;
+
     /// This function should set up and install all interupt and
     /// exception handlers required by the system. It does not return
     /// any errors, and instead should panic on error.
     fn handler_setup();
+
+    /// Whether interrupts are on for this CPU
+    fn interrupts_enabled() -> bool;
+
+    /// Turn interrupts off for this CPU, returning whether they were
+    /// on. Most code wants lock::irqmutex::push_off instead.
+    fn interrupts_off() -> bool;
+
+    /// Turn interrupts on for this CPU. Most code wants
+    /// lock::irqmutex::pop_off instead.
+    fn interrupts_on();
+
+    /// Sleep until an interrupt comes in, and let it be handled even
+    /// if interrupts were off. They are left as they were. May wake
+    /// up for no reason. Don't call it holding an IrqMutex.
+    fn wait_for_interrupt();
+
+    /// Have handler called for external interrupt source irq, at the
+    /// given priority (1 is lowest, the top depends on the
+    /// controller). The source is enabled on every hart to start
+    /// with. Interrupts are completed for you once handler returns.
+    fn register_irq(irq: usize, priority: u32, handler: IrqHandler) -> Result<(), HALIrqError>;
+
+    /// Disable irq and drop its handler
+    fn unregister_irq(irq: usize) -> Result<(), HALIrqError>;
+
+    /// Pick which harts take a registered irq. Harts that aren't
+    /// running yet get it when they start.
+    fn irq_set_harts(irq: usize, harts: HartSet) -> Result<(), HALIrqError>;
+}
+
+/// Handler for an external interrupt, given the source number
+pub type IrqHandler = fn(usize);
+
+#[derive(Debug)]
+pub enum HALIrqError {
+    /// Not a source the interrupt controller has
+    InvalidIrq,
+    InvalidPriority,
+    AlreadyRegistered,
+    NotRegistered,
+}
+
+/// The hardware independent view of a HALIntExc::TrapFrame. Backings
+/// will have more on the frame itself, like the registers.
+pub trait HALTrapFrame {
+    /// Where the trap happened, and where it will return to
+    fn pc(&self) -> usize;
+
+    fn set_pc(&mut self, pc: usize);
+
+    /// The stack pointer of the interrupted code
+    fn sp(&self) -> usize;
+
+    /// Whether the interrupted code was running in user mode
+    fn is_user(&self) -> bool;
+
+    /// The faulting address for memory faults. Meaningless for other
+    /// traps.
+    fn fault_addr(&self) -> usize;
 }
 
 // -------------------------------------------------------------------
@@ -181,6 +417,7 @@ pub trait HALIntExc {
 
 pub enum HALCPUError {
     OutOfCPU,
+    WakeFailed,
 }
 
 pub trait HALCPU {
@@ -202,26 +439,173 @@ pub trait HALCPU {
     /// return an error or nothing to the caller depending on if the
     /// wakeup was successful. A platform should have some other way
     /// of determing the number of CPUs. If a CPU is woken, it's
-    /// execution starts at the passed function.
+    /// execution starts at the passed function, on its own stack and
+    /// with its traps installed, but with paging not yet enabled.
     ///
     /// This should probably only be called after global_setup
-    fn wake_one<F: Fn() -> !>(start: F) -> Result<(), HALCPUError>;
+    fn wake_one<F: Fn() -> ! + Send + 'static>(start: F) -> Result<(), HALCPUError>;
+
+    /// The id of the CPU we are running on. These are small numbers,
+    /// less than HALDiscover::MAX_HARTS, but not necessarily dense.
+    fn hart_id() -> usize;
+
+    /// This CPU's HartLocal. Every CPU has one from before any rust
+    /// runs on it, so this is always valid. Use hartlocal::this_hart
+    /// rather than this.
+    fn hart_local() -> &'static HartLocal;
+}
+
+// -------------------------------------------------------------------
+// Inter-hart communication
+
+/// Which harts a cross hart operation is aimed at. Harts that are not
+/// yet running (or have been halted) are quietly left out.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HartSet {
+    /// Just the calling hart
+    This,
+    /// The hart with this id
+    One(usize),
+    /// Everyone but the calling hart
+    Others,
+    /// Everyone, the calling hart included
+    All,
+}
+
+/// Cross hart calls. Most of the kernel shouldn't need these
+/// directly, the VM calls already shoot down stale translations on
+/// other harts when a mapping goes away.
+pub trait HALIPI {
+    /// Set up IPIs for the calling hart. Called from global_setup on
+    /// the boot hart, secondary harts are handled by wake_one.
+    fn ipi_setup();
+
+    /// Make the harts drop any cached translations for the given
+    /// range. Returns once they have all done so.
+    fn tlb_shootdown(harts: HartSet, virt: VirtAddress, nbytes: usize);
+
+    /// Same as tlb_shootdown, but for every address
+    fn tlb_shootdown_all(harts: HartSet);
+
+    /// Run f on the harts. It runs right away on the calling hart if
+    /// it is in the set, and otherwise asynchronously, in interrupt
+    /// context, so it shouldn't block.
+    fn run_on<F: Fn() + Send + Sync + 'static>(harts: HartSet, f: F);
+
+    /// Stop every other hart for good. Must be safe to call while
+    /// panicking, so it shouldn't allocate or take locks.
+    fn halt_others();
+
+    /// Handle any pending cross hart requests for this hart. For code
+    /// that waits with interrupts off.
+    fn ipi_poll();
+}
+
+// -------------------------------------------------------------------
+// Power control
+
+pub trait HALPower {
+    /// Power off the whole machine. A code of 0 means a clean
+    /// shutdown, anything else is a failure. Where the platform can
+    /// report an exit status (qemu), it passes on at least that
+    /// distinction. Other harts are not cleaned up first.
+    fn shutdown(code: u32) -> !;
+
+    /// Restart the whole machine from scratch
+    fn reboot() -> !;
+}
+
+// -------------------------------------------------------------------
+// Failstate
+
+/// A record of the last panic, kept where it survives a reboot and
+/// can be found with a debugger
+pub trait HALFailstate {
+    /// Write down a panic. This can't allocate, lock or print, as any
+    /// of those might be what's broken.
+    fn failstate_write(info: &PanicInfo);
+
+    /// Print the record a previous boot left, if any, and clear it.
+    fn failstate_report();
+}
+
+// -------------------------------------------------------------------
+// Backtraces
+
+pub trait HALBacktrace {
+    /// Fill frames with the return addresses of the calls that led
+    /// here, innermost first, and give how many there were. Stops
+    /// early at anything that doesn't look like a frame. This can't
+    /// allocate or lock, see crate::backtrace for printing them.
+    fn backtrace(frames: &mut [usize]) -> usize;
 }
 
 // -------------------------------------------------------------------
 
+/// A contiguous range of physical memory
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryRegion {
+    pub start: usize,
+    pub size: usize,
+}
+
+impl MemoryRegion {
+    pub fn end(&self) -> usize {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        addr >= self.start && addr < self.end()
+    }
+}
+
+/// The kinds of memory mapped devices discovery knows how to report
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DeviceKind {
+    Uart,
+    Plic,
+    Clint,
+    Virtio,
+    Rtc,
+    /// qemu's sifive_test, which ends qemu with a pass/fail status
+    Poweroff,
+}
+
+/// A discovered memory mapped device. irq is the interrupt source
+/// number at the platform interrupt controller, if it has one.
+#[derive(Clone, Copy, Debug)]
+pub struct MmioDevice {
+    pub base: usize,
+    pub size: usize,
+    pub irq: Option<u32>,
+}
+
 /// This wraps all hardware discovery.
 ///
-/// TODO when we do device tree stuff, it will be exposed here
+/// Everything other than the associated consts is only valid after
+/// discover_setup, which should be one of the first things in
+/// global_setup, as other setup depends on it.
 pub trait HALDiscover {
     fn discover_setup();
 
-    // Const in the short term, but we will see
-    const NHART: usize;
+    /// The most harts the kernel image has room for (stacks etc.).
+    /// This is a compile time limit, the real number comes from
+    /// nhart.
+    const MAX_HARTS: usize;
+
+    /// The number of harts this machine has that we will use. Never
+    /// more than MAX_HARTS
+    fn nhart() -> usize;
 
-    // I think this is safe to be a const. It can change when we have
-    // reason for it.
-    const DRAM_BASE: *mut usize;
+    /// Start of the main memory region, the one the kernel lives in
+    fn dram_base() -> *mut usize;
+
+    /// All the main memory regions of the machine
+    fn memory_regions() -> &'static [MemoryRegion];
+
+    /// All the discovered devices of a given kind, in discovery
+    /// order. Empty if there are none.
+    fn mmio_devices(kind: DeviceKind) -> &'static [MmioDevice];
 }
 
 // -------------------------------------------------------------------
@@ -250,6 +634,10 @@ pub trait HALSections {
     fn bss_start() -> *mut usize;
     fn bss_end() -> *mut usize;
 
+    /// Where memory free for allocation starts. At or past bss_end,
+    /// as the backing may keep some for itself in between.
+    fn heap_start() -> *mut usize;
+
     // TODO deprecate? wait for clarity as for context switches under
     // opensbi
     fn memory_start() -> *mut usize;
@@ -261,28 +649,22 @@ pub trait HALSections {
 
 /// Trait the collect context switching stuff
 pub trait HALSwitch {
-    /// This type should contain the initial info that needs to be
-    /// presereved through a context switch. This should not include
-    /// things like the kernel page table which from the point of view
-    /// of the main kernel should be switched automatically, but
-    /// should contian things like the current process. Think of it
-    /// like the arguments to the first function after a context
-    /// switch.
-    ///
-    /// The implementation should provide a method of creating a
-    /// GPInfo structure, including a Process object to represent the
-    /// currently executing process.
-    type GPInfo;
-
     /// called once before any of the switching occurs, just like the
     /// rest.
     fn switch_setup();
 
-    /// Set the structure for the next restore on this CPU.
-    fn save_gp_info(gpi: Self::GPInfo);
+    /// Keep the process we are about to switch to until we are back
+    /// in the kernel. Only one can be kept per CPU, on its HartLocal.
+    fn save_process(proc: Process);
+
+    /// Take back the process this CPU switched to last. Only valid
+    /// once per save_process, after coming back from the process.
+    fn restore_process() -> Process;
 
-    /// Restore the most recently saved structure on this CPU.
-    fn restore_gp_info() -> Self::GPInfo;
+    /// Where a syscall's return value goes in the registers saved on
+    /// the process stack, as an offset from the process sp passed to
+    /// scall_rust_standard.
+    const SCALL_RETURN_OFFSET: usize;
 
     // I think it makes sense for the unsafe / extern C boundary into
     // the asm to be in the hal, so the main kernel just sees a safe
@@ -305,7 +687,9 @@ pub trait HALBacking:
 HALSerial + HALTimer +
     HALVM + HALIntExc +
     HALCPU + HALDiscover +
-    HALSections + HALSwitch
+    HALSections + HALSwitch +
+    HALIPI + HALPower + HALFailstate +
+    HALBacktrace
 {
     /// Call on all CPUs on start, a single one will exit, and all others will hold, until a later wakeup call
 
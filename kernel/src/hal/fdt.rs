@@ -0,0 +1,448 @@
+//! Flattened device tree (FDT / DTB) parsing.
+//!
+//! This is a read-only, allocation free walker over a device tree
+//! blob, as handed to us by firmware. It does not build any kind of
+//! tree in memory, it just walks the structure block on demand. That
+//! means it is safe to use before the page allocator is up, which is
+//! exactly when HAL discovery needs it.
+//!
+//! It is not specific to any one backing, each HAL backing that gets
+//! a device tree decides for itself what it wants to pull out of it.
+//!
+//! REFS: <https://github.com/devicetree-org/devicetree-specification/releases>
+//! (Chapter 5, Flattened Devicetree Format)
+
+use core::str::from_utf8;
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+/// Deepest node nesting we keep cell sizes for. Real trees are
+/// nowhere near this.
+const MAX_DEPTH: usize = 16;
+
+/// Defaults from the spec when a parent does not say otherwise
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+const DEFAULT_SIZE_CELLS: u32 = 1;
+
+/// Things that can go wrong reading a blob
+#[derive(Debug)]
+pub enum FdtError {
+    NullPointer,
+    BadMagic(u32),
+    UnsupportedVersion(u32),
+    Truncated,
+}
+
+/// Read a big endian u32 out of a slice at a byte offset, if it fits
+fn be32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let raw = bytes.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]))
+}
+
+/// Round up to the 4 byte alignment the structure block uses
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// Read a nul terminated string starting at offset. Does not include
+/// the terminator
+fn cstr(bytes: &[u8], offset: usize) -> Option<&str> {
+    let rest = bytes.get(offset..)?;
+    let len = rest.iter().position(|b| *b == 0)?;
+    from_utf8(&rest[..len]).ok()
+}
+
+/// A validated device tree blob.
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+    boot_cpuid: u32,
+    size: usize,
+}
+
+impl<'a> Fdt<'a> {
+    /// Check the header of the blob at ptr and wrap it.
+    ///
+    /// # Safety
+    ///
+    /// ptr must be null or point to readable memory at least as long
+    /// as the header says the blob is, for all of 'a. Nothing else
+    /// may write to that memory for 'a either.
+    pub unsafe fn from_ptr(ptr: *const u8) -> Result<Self, FdtError> {
+        if ptr.is_null() {
+            return Err(FdtError::NullPointer);
+        }
+        // the header is 10 u32s, look at just it first to learn the
+        // real size
+        let header = core::slice::from_raw_parts(ptr, 40);
+        let magic = be32(header, 0).unwrap();
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let total = be32(header, 4).unwrap() as usize;
+        let blob = core::slice::from_raw_parts(ptr, total);
+        Self::from_bytes(blob)
+    }
+
+    /// Wrap an in memory copy of a blob.
+    pub fn from_bytes(blob: &'a [u8]) -> Result<Self, FdtError> {
+        let field = |n: usize| be32(blob, n * 4).ok_or(FdtError::Truncated);
+        let magic = field(0)?;
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let total = field(1)? as usize;
+        let struct_off = field(2)? as usize;
+        let strings_off = field(3)? as usize;
+        // we only understand the v17 layout, but anything that claims
+        // to be backwards compatible with 16 is fine
+        let last_compatible = field(6)?;
+        if last_compatible > 17 {
+            return Err(FdtError::UnsupportedVersion(last_compatible));
+        }
+        let boot_cpuid = field(7)?;
+        let strings_size = field(8)? as usize;
+        let struct_size = field(9)? as usize;
+
+        if total > blob.len() {
+            return Err(FdtError::Truncated);
+        }
+        let structs = blob.get(struct_off..struct_off + struct_size)
+            .ok_or(FdtError::Truncated)?;
+        let strings = blob.get(strings_off..strings_off + strings_size)
+            .ok_or(FdtError::Truncated)?;
+        Ok(Self {
+            structs,
+            strings,
+            boot_cpuid,
+            size: total,
+        })
+    }
+
+    /// Total size of the blob in bytes, header included
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Physical id of the cpu the firmware booted us on
+    pub fn boot_cpuid(&self) -> u32 {
+        self.boot_cpuid
+    }
+
+    /// Walk every node in the tree, depth first, in blob order. The
+    /// root is the first node yielded.
+    pub fn nodes(&self) -> FdtNodeIter<'a> {
+        FdtNodeIter {
+            fdt: *self,
+            offset: 0,
+            depth: 0,
+            cells: [(DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS); MAX_DEPTH],
+            done: false,
+        }
+    }
+
+    /// Find the first node with a given compatible string
+    pub fn find_compatible(&self, compat: &str) -> Option<FdtNode<'a>> {
+        self.nodes().find(|n| n.is_compatible(compat))
+    }
+
+    /// Find a node by its full path from the root, like "/cpus" or
+    /// "/soc/plic@c000000". Unit addresses may be left off if they
+    /// are not needed to be unique, like "/chosen".
+    pub fn find_path(&self, path: &str) -> Option<FdtNode<'a>> {
+        let mut want = path.split('/').filter(|s| !s.is_empty());
+        let mut next = want.next();
+        let mut matched_depth = 0;
+        for node in self.nodes() {
+            if node.depth == 0 {
+                if next.is_none() {
+                    return Some(node);
+                }
+                continue;
+            }
+            if node.depth <= matched_depth {
+                // walked out of the subtree we were matching in
+                return None;
+            }
+            if node.depth != matched_depth + 1 {
+                continue;
+            }
+            let component = next?;
+            if node.name() == component || node.base_name() == component {
+                matched_depth += 1;
+                next = want.next();
+                if next.is_none() {
+                    return Some(node);
+                }
+            }
+        }
+        None
+    }
+
+    fn string_at(&self, offset: usize) -> Option<&'a str> {
+        cstr(self.strings, offset)
+    }
+}
+
+/// A single node in the tree. Cheap to copy, it is just an offset.
+#[derive(Clone, Copy)]
+pub struct FdtNode<'a> {
+    fdt: Fdt<'a>,
+    name: &'a str,
+    /// offset of the first token after the name
+    props: usize,
+    /// root is depth 0
+    pub depth: usize,
+    /// #address-cells and #size-cells of the parent, which is what
+    /// decodes this node's reg property
+    parent_cells: (u32, u32),
+    /// #address-cells and #size-cells this node sets for its children
+    own_cells: (u32, u32),
+}
+
+impl<'a> FdtNode<'a> {
+    /// The full node name, including any unit address, like
+    /// "uart@10000000". The root is the empty string.
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    /// The node name without the unit address, like "uart"
+    pub fn base_name(&self) -> &'a str {
+        self.name.split('@').next().unwrap_or(self.name)
+    }
+
+    /// The #address-cells and #size-cells this node sets for its
+    /// children
+    pub fn child_cells(&self) -> (u32, u32) {
+        self.own_cells
+    }
+
+    /// Iterate over this node's properties
+    pub fn props(&self) -> FdtPropIter<'a> {
+        FdtPropIter {
+            fdt: self.fdt,
+            offset: self.props,
+        }
+    }
+
+    /// Raw value of a named property
+    pub fn prop(&self, name: &str) -> Option<&'a [u8]> {
+        self.props().find(|p| p.name == name).map(|p| p.value)
+    }
+
+    /// A property holding a single u32 cell
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        be32(self.prop(name)?, 0)
+    }
+
+    /// A property holding one or two cells, like clock frequencies
+    pub fn prop_u64(&self, name: &str) -> Option<u64> {
+        let raw = self.prop(name)?;
+        read_cells(raw, 0, (raw.len() / 4) as u32)
+    }
+
+    /// A property holding a single string
+    pub fn prop_str(&self, name: &str) -> Option<&'a str> {
+        cstr(self.prop(name)?, 0)
+    }
+
+    /// Check if any entry of this node's compatible list matches
+    pub fn is_compatible(&self, compat: &str) -> bool {
+        match self.prop("compatible") {
+            None => false,
+            Some(list) => list.split(|b| *b == 0)
+                .any(|entry| entry == compat.as_bytes()),
+        }
+    }
+
+    /// Nodes are enabled unless they have a status other than "okay"
+    pub fn is_enabled(&self) -> bool {
+        match self.prop_str("status") {
+            None => true,
+            Some(s) => s == "okay" || s == "ok",
+        }
+    }
+
+    /// Iterate the (address, size) pairs of the reg property, decoded
+    /// with the parent's cell sizes. Empty if there is no reg.
+    pub fn reg(&self) -> FdtRegIter<'a> {
+        FdtRegIter {
+            raw: self.prop("reg").unwrap_or(&[]),
+            offset: 0,
+            cells: self.parent_cells,
+        }
+    }
+
+    /// The first interrupt specifier cell, which is the source number
+    /// for the interrupt controllers we care about (the PLIC)
+    pub fn first_interrupt(&self) -> Option<u32> {
+        self.prop_u32("interrupts")
+    }
+}
+
+/// A single property of a node
+#[derive(Clone, Copy)]
+pub struct FdtProp<'a> {
+    pub name: &'a str,
+    pub value: &'a [u8],
+}
+
+pub struct FdtPropIter<'a> {
+    fdt: Fdt<'a>,
+    offset: usize,
+}
+
+impl<'a> Iterator for FdtPropIter<'a> {
+    type Item = FdtProp<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match be32(self.fdt.structs, self.offset)? {
+                FDT_NOP => {
+                    self.offset += 4;
+                },
+                FDT_PROP => {
+                    let len = be32(self.fdt.structs, self.offset + 4)? as usize;
+                    let name_off = be32(self.fdt.structs, self.offset + 8)? as usize;
+                    let start = self.offset + 12;
+                    let value = self.fdt.structs.get(start..start + len)?;
+                    self.offset = align4(start + len);
+                    return Some(FdtProp {
+                        name: self.fdt.string_at(name_off)?,
+                        value,
+                    });
+                },
+                // properties always come before child nodes, so
+                // anything else is the end of this node's list
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Read `count` big endian cells as one number. More than two cells
+/// does not fit and reads as None.
+fn read_cells(raw: &[u8], offset: usize, count: u32) -> Option<u64> {
+    match count {
+        0 => Some(0),
+        1 => be32(raw, offset).map(|v| v as u64),
+        2 => Some(((be32(raw, offset)? as u64) << 32) | be32(raw, offset + 4)? as u64),
+        _ => None,
+    }
+}
+
+pub struct FdtRegIter<'a> {
+    raw: &'a [u8],
+    offset: usize,
+    cells: (u32, u32),
+}
+
+impl<'a> Iterator for FdtRegIter<'a> {
+    /// (address, size)
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (address_cells, size_cells) = self.cells;
+        // empty entries would never move us along
+        if address_cells == 0 && size_cells == 0 {
+            return None;
+        }
+        let address = read_cells(self.raw, self.offset, address_cells)?;
+        let size = read_cells(self.raw, self.offset + 4 * address_cells as usize, size_cells)?;
+        self.offset += 4 * (address_cells + size_cells) as usize;
+        Some((address, size))
+    }
+}
+
+/// Depth first walk over every node of the structure block
+pub struct FdtNodeIter<'a> {
+    fdt: Fdt<'a>,
+    offset: usize,
+    depth: usize,
+    /// the cells each open node sets for its children
+    cells: [(u32, u32); MAX_DEPTH],
+    done: bool,
+}
+
+impl<'a> Iterator for FdtNodeIter<'a> {
+    type Item = FdtNode<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let token = match be32(self.fdt.structs, self.offset) {
+                Some(t) => t,
+                None => {
+                    self.done = true;
+                    return None;
+                }
+            };
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = cstr(self.fdt.structs, self.offset + 4)?;
+                    let props = align4(self.offset + 4 + name.len() + 1);
+                    let depth = self.depth;
+                    if depth >= MAX_DEPTH {
+                        self.done = true;
+                        return None;
+                    }
+                    let parent_cells = if depth == 0 {
+                        (DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS)
+                    } else {
+                        self.cells[depth - 1]
+                    };
+                    let mut node = FdtNode {
+                        fdt: self.fdt,
+                        name,
+                        props,
+                        depth,
+                        parent_cells,
+                        own_cells: (DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS),
+                    };
+                    // skip over our properties while looking for the
+                    // cell sizes we set for our children
+                    let mut props = node.props();
+                    for p in props.by_ref() {
+                        match p.name {
+                            "#address-cells" => node.own_cells.0 = be32(p.value, 0)?,
+                            "#size-cells" => node.own_cells.1 = be32(p.value, 0)?,
+                            _ => {},
+                        }
+                    }
+                    self.cells[depth] = node.own_cells;
+                    self.offset = props.offset;
+                    self.depth += 1;
+                    return Some(node);
+                },
+                FDT_END_NODE => {
+                    self.depth = self.depth.saturating_sub(1);
+                    self.offset += 4;
+                },
+                FDT_PROP => {
+                    // only reachable for malformed trees, props are
+                    // consumed along with their node above
+                    let len = be32(self.fdt.structs, self.offset + 4)? as usize;
+                    self.offset = align4(self.offset + 12 + len);
+                },
+                FDT_NOP => {
+                    self.offset += 4;
+                },
+                FDT_END => {
+                    self.done = true;
+                },
+                _ => {
+                    // garbage, give up rather than guess
+                    self.done = true;
+                },
+            }
+        }
+        None
+    }
+}
@@ -1,28 +1,34 @@
 /// This module is conditionally included and contains the HAL backing
 /// for the qemu riscv 'virt' machine. It assumes that the kernel is
 /// booted in S mode by uboot and is running on top of opensbi.
+///
+/// With hal-sifive-u the same backing runs on the qemu 'sifive_u'
+/// machine instead. What differs between the two is in board.rs.
+///
+/// With hal-virt-bare there is no opensbi, and mmode.rs takes its
+/// place underneath everything else here.
 
 // Useful:
 //
 // https://github.com/riscv-non-isa/riscv-sbi-doc/blob/master/riscv-sbi.adoc
 
-// TODO
-//
-// add failstate logging, for use with panics. (i.e. write the most
-// recent panic message to a fixed point in memory so we can read it
-// with a debugger if that issue is so bad that our printing doesn't
-// work) Not a priority
-
 use core::arch::asm;
 use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
 use alloc::vec;
 
 use super::*;
 use crate::vm::{palloc, pfree};
 
-use crate::process::scall_rust_standard;
+use crate::process::{scall_rust_standard, process_fault_rust};
 
 mod asm;
+mod board;
+
+#[cfg(feature = "hal-virt-bare")]
+mod mmode;
 
 // -------------------------------------------------------------------
 // Shim for misc bused riscv stuff
@@ -43,6 +49,10 @@ pub fn fmodf(_a: f32, _b: f32) -> f32 {
 
 const DEBUG_EID: u32 = 0x4442434E;
 const BASE_EID: u32 = 0x10;
+const TIME_EID: u32 = 0x54494D45;
+const HSM_EID: u32 = 0x48534D;
+const SRST_EID: u32 = 0x53525354;
+const LEGACY_SHUTDOWN_EID: u32 = 0x08;
 
 const SBI_SUCCESS: i32               =  0; // Completed successfully
 const SBI_ERR_FAILED: i32            = -1; // Failed
@@ -55,6 +65,51 @@ const SBI_ERR_ALREADY_STARTED: i32   = -7; // Already started
 const SBI_ERR_ALREADY_STOPPED: i32   = -8; // Already stopped
 const SBI_ERR_NO_SHMEM: i32          = -9; // Shared memory not available
 
+/// A failed opensbi call, from the SBI_ERR_* codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SbiError {
+    Failed,
+    NotSupported,
+    InvalidParam,
+    Denied,
+    InvalidAddress,
+    AlreadyAvailable,
+    AlreadyStarted,
+    AlreadyStopped,
+    NoShmem,
+    /// Something newer than we know about
+    Unknown(i32),
+}
+
+impl SbiError {
+    /// Ok for SBI_SUCCESS, the error for anything else
+    fn check(code: i32) -> Result<(), SbiError> {
+        match code {
+            SBI_SUCCESS => Ok(()),
+            SBI_ERR_FAILED => Err(SbiError::Failed),
+            SBI_ERR_NOT_SUPPORTED => Err(SbiError::NotSupported),
+            SBI_ERR_INVALID_PARAM => Err(SbiError::InvalidParam),
+            SBI_ERR_DENIED => Err(SbiError::Denied),
+            SBI_ERR_INVALID_ADDRESS => Err(SbiError::InvalidAddress),
+            SBI_ERR_ALREADY_AVAILABLE => Err(SbiError::AlreadyAvailable),
+            SBI_ERR_ALREADY_STARTED => Err(SbiError::AlreadyStarted),
+            SBI_ERR_ALREADY_STOPPED => Err(SbiError::AlreadyStopped),
+            SBI_ERR_NO_SHMEM => Err(SbiError::NoShmem),
+            code => Err(SbiError::Unknown(code)),
+        }
+    }
+}
+
+impl From<SbiError> for HALSerialError {
+    fn from(err: SbiError) -> Self {
+        match err {
+            SbiError::NotSupported | SbiError::Denied => HALSerialError::Unavailable,
+            SbiError::Failed => HALSerialError::Io,
+            _ => HALSerialError::Other,
+        }
+    }
+}
+
 fn _opensbi_call(eid: usize, fid: usize, mut a0: usize, mut a1: usize, a2: usize, a3: usize) -> (i32, u32) {
     unsafe {
         asm!(
@@ -77,138 +132,214 @@ fn opensbi_call(eid: u32, fid: u32, a0: u32, a1: u32, a2: u32, a3: u32) -> (i32,
 // -------------------------------------------------------------------
 // Trait implementations
 
-impl HALSerial for HAL {
-    fn serial_setup() {
+// Serial is either the opensbi debug console, or the UART directly
+// with the uart-ns16550a feature. On sifive_u it's always the UART.
+// Either way serial.rs buffers it.
+
+mod serial;
+
+#[cfg(feature = "uart-ns16550a")]
+mod uart;
+
+#[cfg(feature = "hal-sifive-u")]
+mod sifive_uart;
+
+#[cfg(not(any(feature = "uart-ns16550a", feature = "hal-sifive-u")))]
+type Console = SbiConsole;
+#[cfg(feature = "uart-ns16550a")]
+type Console = uart::UartConsole;
+#[cfg(feature = "hal-sifive-u")]
+type Console = sifive_uart::SifiveUartConsole;
+
+/// The opensbi debug console extension
+#[cfg(not(any(feature = "uart-ns16550a", feature = "hal-sifive-u")))]
+struct SbiConsole;
+
+#[cfg(not(any(feature = "uart-ns16550a", feature = "hal-sifive-u")))]
+impl serial::RawSerial for SbiConsole {
+    // opensbi can't tell us about input
+    const HAS_IRQ: bool = false;
+
+    fn setup() -> Result<(), HALSerialError> {
         // probe for opensbi debug console extension
         let (err, val) = opensbi_call(BASE_EID, 3, DEBUG_EID, 0, 0, 0);
-        match err {
-            0 => {
-                // all good!
-                match val {
-                    0 => {
-                        panic!("Opensbi does not support debug logging!");
-                    },
-                    1 => {
-                        // exactly as we want
-                    },
-                    _ => {
-                        panic!("Unexpected opensbi return code!");
-                    }
-                }
-            },
-            SBI_ERR_FAILED | SBI_ERR_NOT_SUPPORTED | SBI_ERR_DENIED => {
-                panic!("Unsupported base opensbi extension!");
-            },
-            _ => {
-                panic!("Unexpected opensbi error code!");
-            }
+        SbiError::check(err)?;
+        if val == 0 {
+            Err(HALSerialError::Unavailable)
+        } else {
+            Ok(())
         }
     }
 
-    fn serial_put_char(c: char) {
-        let mut buffer: [u8; 4] = [0; 4];
-        let slice = c.encode_utf8(&mut buffer);
-        for iter in slice.as_bytes() {
-            let val: u8 = iter.clone();
-            // opensbi console putchar
-            let (err, _) = opensbi_call(DEBUG_EID, 2, val as u32, 0, 0, 0);
-            match err {
-                0 => {},
-                SBI_ERR_FAILED => {
-                    panic!("Failed to write to console!");
-                },
-                _ => {
-                    panic!("Unexpected opensbi error code!");
-                }
-            }
+    fn put_bytes(bytes: &[u8]) -> Result<(), HALSerialError> {
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let (err, written) = opensbi_call(DEBUG_EID, 0,
+                         rest.len() as u32,
+                         ((rest.as_ptr() as usize) & 0xFF_FF_FF_FF) as u32, // low bits
+                         ((rest.as_ptr() as usize) >> 32) as u32,                  // high bits
+                         0,
+            );
+            SbiError::check(err)?;
+            // it's allowed to take less than all of it
+            rest = &rest[written as usize..];
         }
+        Ok(())
     }
 
-    fn serial_read_byte() -> u8 {
+    fn get_byte() -> Result<Option<u8>, HALSerialError> {
         let mut val: u8 = 0;
-        // opensbi console getchar
-        let (err, _ret) = opensbi_call(DEBUG_EID, 1,
+        // opensbi console read, which doesn't wait
+        let (err, count) = opensbi_call(DEBUG_EID, 1,
                      1,         // 1 byte
                      ((&mut val as *mut u8 as usize) & 0xFF_FF_FF_FF) as u32, // low bits
                      ((&mut val as *mut u8 as usize) >> 32) as u32,                  // high bits
                      0,
         );
-        match err {
-            SBI_SUCCESS => {},
-            SBI_ERR_FAILED => {
-                panic!("Opensbi I/O fail on read");
-            }
-            SBI_ERR_INVALID_PARAM => {
-                panic!("Opensbi didn't like arguments to console read");
-            },
-            _ => {
-                panic!("Unexpected opensbi error code");
-            }
+        SbiError::check(err)?;
+        if count == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(val))
         }
-        val
     }
+}
 
-    fn serial_put_string(s: &str) {
-        let (err, _ret) = opensbi_call(DEBUG_EID, 0,
-                     s.len() as u32,         // 1 byte
-                     ((s.as_ptr() as usize) & 0xFF_FF_FF_FF) as u32, // low bits
-                     ((s.as_ptr() as usize) >> 32) as u32,                  // high bits
-                     0,
-        );
-        match err {
-            SBI_SUCCESS => {},
-            SBI_ERR_FAILED => {
-                panic!("Opensbi I/O fail on write");
-            }
-            SBI_ERR_INVALID_PARAM => {
-                panic!("Opensbi didn't like arguments to console write");
-            },
-            _ => {
-                panic!("Unexpected opensbi error code");
-            }
-        }
+impl HALSerial for HAL {
+    fn serial_setup() -> Result<(), HALSerialError> {
+        serial::setup()
+    }
 
+    fn serial_put_char(c: char) -> Result<(), HALSerialError> {
+        let mut buffer: [u8; 4] = [0; 4];
+        serial::put_bytes(c.encode_utf8(&mut buffer).as_bytes())
     }
 
-    // TODO pass errors back up
-    fn serial_read_bytes(buf: &mut [u8], num: u32) {
-        let (err, _ret) = opensbi_call(DEBUG_EID, 1,
-                     num,         // 1 byte
-                     ((buf.as_mut_ptr() as usize) & 0xFF_FF_FF_FF) as u32, // low bits
-                     ((buf.as_mut_ptr() as usize) >> 32) as u32,                  // high bits
-                     0,
-        );
-        match err {
-            SBI_SUCCESS => {},
-            SBI_ERR_FAILED => {
-                panic!("Opensbi I/O fail on read");
-            }
-            SBI_ERR_INVALID_PARAM => {
-                panic!("Opensbi didn't like arguments to console read");
-            },
-            _ => {
-                panic!("Unexpected opensbi error code");
-            }
+    fn serial_read_byte() -> Result<u8, HALSerialError> {
+        serial::read_byte()
+    }
+
+    fn serial_put_string(s: &str) -> Result<(), HALSerialError> {
+        serial::put_bytes(s.as_bytes())
+    }
+
+    fn serial_read_bytes(buf: &mut [u8], num: u32) -> Result<(), HALSerialError> {
+        for byte in buf.iter_mut().take(num as usize) {
+            *byte = serial::read_byte()?;
         }
+        Ok(())
+    }
 
+    fn serial_try_read_byte() -> Result<Option<u8>, HALSerialError> {
+        serial::try_read()
+    }
+
+    fn serial_read_nonblocking(buf: &mut [u8]) -> Result<usize, HALSerialError> {
+        serial::read_nonblocking(buf)
+    }
+
+    fn serial_read_line(buf: &mut [u8]) -> Result<usize, HALSerialError> {
+        serial::read_line(buf)
+    }
+
+    fn serial_input_ready() -> Result<bool, HALSerialError> {
+        serial::input_ready()
+    }
+
+    fn serial_on_input(notify: fn()) {
+        serial::on_input(notify);
+    }
+
+    fn serial_poll() -> Result<(), HALSerialError> {
+        serial::poll()
+    }
+
+    fn serial_flush() -> Result<(), HALSerialError> {
+        serial::flush()
+    }
+
+    fn serial_panic_put_string(s: &str) -> Result<(), HALSerialError> {
+        serial::panic_put_bytes(s.as_bytes())
     }
 }
 
 // -------------------------------------------------------------------
 
+/// Check if opensbi implements an extension
+fn opensbi_probe(eid: u32) -> bool {
+    let (err, val) = opensbi_call(BASE_EID, 3, eid, 0, 0, 0);
+    err == SBI_SUCCESS && val != 0
+}
+
+fn read_time() -> u64 {
+    let out: u64;
+    unsafe {
+        asm!(
+            "rdtime {out}",
+            out = out(reg) out
+        );
+    }
+    out
+}
+
+/// Ask opensbi to raise a supervisor timer interrupt once time passes
+/// deadline. This also clears any pending timer interrupt.
+fn opensbi_set_timer(deadline: u64) {
+    // the full 64 bit value goes in a0, so we can't use the u32
+    // wrapper here
+    let (err, _) = _opensbi_call(TIME_EID as usize, 0, deadline as usize, 0, 0, 0);
+    if err != SBI_SUCCESS {
+        panic!("Unexpected opensbi error code setting a timer: {}", err);
+    }
+}
+
+const SIE_STIE: usize = 1 << 5;
+
 impl HALTimer for HAL {
-    // TODO we need mtime for mtimecmp type stuff here. See the comment in hal.rs
     fn timer_setup() {
-        log!(Error, "WE HAVEN'T IMPLEMENTED TIMERS YET!!!");
+        if !opensbi_probe(TIME_EID) {
+            panic!("Opensbi does not support the timer extension!");
+        }
+        // nothing pending to start with
+        Self::timer_clear();
+        unsafe {
+            // let the timer interrupt through once SIE is set
+            asm!(
+                "csrs sie, {stie}",
+                stie = in(reg) SIE_STIE
+            );
+        }
+        log!(Debug, "Timer running at {} ticks per second", Self::timebase_frequency());
+    }
+
+    fn timer_set(ticks: u64) {
+        opensbi_set_timer(read_time().saturating_add(ticks));
+    }
+
+    fn timer_clear() {
+        // There is no cancel call, but a deadline that never comes is
+        // just as good
+        opensbi_set_timer(u64::MAX);
+    }
+
+    fn now() -> u64 {
+        read_time()
+    }
+
+    fn timebase_frequency() -> u64 {
+        discover::machine().timebase_frequency
     }
 
-    fn timer_set(_ticks: u64) {
-        todo!("Add timers bucko.")
+    fn wall_clock() -> Option<Duration> {
+        rtc::read()
     }
 }
 
+mod rtc;
+
 // -------------------------------------------------------------------
 mod ptable;
+mod asid;
 
 fn flags_hal_to_ptable(general: PageMapFlags) -> Result<usize, HALVMError> {
     let mut out: usize = 0;
@@ -246,6 +377,41 @@ fn flags_hal_to_ptable(general: PageMapFlags) -> Result<usize, HALVMError> {
     return Ok(out);
 }
 
+fn flags_ptable_to_hal(pte: usize) -> PageMapFlags {
+    let mut out = PageMapFlags::empty();
+    for (bit, flag) in [
+        (ptable::PTE_READ, PageMapFlags::Read),
+        (ptable::PTE_WRITE, PageMapFlags::Write),
+        (ptable::PTE_EXEC, PageMapFlags::Execute),
+        (ptable::PTE_VALID, PageMapFlags::Valid),
+        (ptable::PTE_USER, PageMapFlags::User),
+        (ptable::PTE_GLOBAL, PageMapFlags::Global),
+        (ptable::PTE_ACCESSED, PageMapFlags::Accessed),
+        (ptable::PTE_DIRTY, PageMapFlags::Dirty),
+    ] {
+        if pte & bit != 0 {
+            out |= flag;
+        }
+    }
+    out
+}
+
+/// See HALVM::pgtbl_mappings
+pub struct PgtblMappings(ptable::MappingIter);
+
+impl Iterator for PgtblMappings {
+    type Item = PgtblMapping;
+
+    fn next(&mut self) -> Option<PgtblMapping> {
+        self.0.next().map(|m| PgtblMapping {
+            virt: m.va,
+            phys: m.pa,
+            nbytes: m.size,
+            flags: flags_ptable_to_hal(m.flags),
+        })
+    }
+}
+
 fn table_hal_to_ptable(general: PageTable) -> ptable::PageTable {
     ptable::PageTable {
         base: general.addr,
@@ -254,49 +420,45 @@ fn table_hal_to_ptable(general: PageTable) -> ptable::PageTable {
 
 impl HALVM for HAL {
     fn pgtbl_setup() {
-        // I don't think I need any global setup. Kernel page table creation happens later.
+        // Kernel page table creation happens later, we just need to
+        // know what kind of tables to make
+        let (mode, asid_bits) = ptable::probe_paging();
+        ptable::set_paging_mode(mode);
+        asid::init(asid_bits);
+        log!(Info, "Using {:?} paging with {} ASID bits", mode, asid_bits);
     }
 
-    fn kernel_reserved_areas() -> Vec<(PhysPageExtent, PageMapFlags)> {
+    fn kernel_reserved_areas() -> Vec<(usize, usize, PageMapFlags)> {
         // It's not clear what of this might / should be handled by opensbi
 
-        const CLINT_BASE: usize = 0x2000000;
         // We don't map the CLINT because we can use opensbi for that
-        // (timers). I don't know whether we can/should be mapping the
-        // PLIC either.
-
-        const UART_BASE: usize = 0x10000000;
-        const UART_SIZE: usize = PAGE_SIZE;
-
-        const PLIC_BASE: usize = 0xc000000;
-        const PLIC_SIZE: usize = 0x400000 / PAGE_SIZE;
-
-        const VIRTIO_BASE:usize = 0x10001000;
-        const VIRTIO_SIZE: usize = 0x4000 / PAGE_SIZE;
-
-        // TODO one of these mappings is causing issues? I think it
-        // might be overruling some opensbi firmware? totally
-        // unclear. leave them out until it's clear we need them. I
-        // think only PLIC is required, for virtio, as the others are
-        // covered by opensbi serial+timers
-        vec!(
-        //     (PhysPageExtent::new(UART_BASE, PAGE_SIZE), PageMapFlags::Read | PageMapFlags::Write),
-        //     (PhysPageExtent::new(PLIC_BASE, PLIC_SIZE), PageMapFlags::Read | PageMapFlags::Write),
-        //     (PhysPageExtent::new(VIRTIO_BASE, VIRTIO_SIZE), PageMapFlags::Read | PageMapFlags::Write),
-        )
+        // (timers). The PLIC is ours though, it's where external
+        // interrupts get claimed.
+        let (plic_base, plic_size) = plic::region();
+        let mut areas = vec!(
+            (plic_base, plic_size.div_ceil(PAGE_SIZE), PageMapFlags::Read | PageMapFlags::Write),
+        );
+        // unless we are driving the UART ourselves
+        if let Some(base) = <Console as serial::RawSerial>::MMIO_BASE {
+            areas.push((base, 1, PageMapFlags::Read | PageMapFlags::Write));
+        }
+        areas.push((failstate::start(), failstate::pages(), PageMapFlags::Read | PageMapFlags::Write));
+        if let Some(rtc) = HAL::mmio_devices(DeviceKind::Rtc).first() {
+            areas.push((rtc.base, 1, PageMapFlags::Read));
+        }
+        // shutdown writes it directly, see HALPower::shutdown
+        if let Some(poweroff) = HAL::mmio_devices(DeviceKind::Poweroff).first() {
+            areas.push((poweroff.base, 1, PageMapFlags::Read | PageMapFlags::Write));
+        }
+        areas
     }
 
 
     fn kernel_pgtbl_late_setup(pgtbl: &PageTable) {
-        unsafe {
-            asm!(
-                "csrrw sp, sscratch, sp",
-                // space has already been reserved for us, we should write to sp+8
-                "sd {page_table}, 8(sp)",
-                "csrrw sp, sscratch, sp",
-                page_table = in(reg) pgtbl.addr as usize
-            );
-        }
+        // for the trap path to switch to. This is the full satp,
+        // paging mode included, so the asm doesn't have to know it
+        hartlocal::current().trap_scratch[hartlocal::SCRATCH_KERNEL_SATP]
+            .set(ptable::satp_for(pgtbl.addr));
     }
 
     /// This call is only valid after other non-hal stuff has been
@@ -313,8 +475,17 @@ impl HALVM for HAL {
         }
     }
 
-    fn pgtbl_deep_copy(_src: PageTable,_dest: PageTable) -> Result<(), HALVMError> {
-        todo!("Walk page table and copy as necessary.")
+    fn pgtbl_deep_copy(src: PageTable, dest: PageTable, mode: PgtblCopyMode) -> Result<Vec<PhysPageExtent>, HALVMError> {
+        match ptable::deep_copy(
+            table_hal_to_ptable(src),
+            table_hal_to_ptable(dest),
+            mode == PgtblCopyMode::DuplicateLeaves
+        ) {
+            Ok(copies) => Ok(copies.into_iter()
+                             .map(|page| PhysPageExtent::new(page.addr as usize, 1))
+                             .collect()),
+            Err(_) => Err(HALVMError::FailedAllocation),
+        }
     }
 
     fn pgtbl_insert_range(
@@ -337,39 +508,65 @@ impl HALVM for HAL {
         }
     }
 
+    fn pgtbl_lookup(pgtbl: &PageTable, virt: VirtAddress) -> Option<(PhysAddress, PageMapFlags)> {
+        let (phys, flags) = ptable::lookup(ptable::PageTable::new(pgtbl.addr), virt)?;
+        Some((phys, flags_ptable_to_hal(flags)))
+    }
+
+    fn pgtbl_protect_range(
+        pgtbl: PageTable,
+        virt: VirtAddress,
+        nbytes: usize,
+        flags: PageMapFlags
+    ) -> Result<(), HALVMError> {
+        let pte_flags = flags_hal_to_ptable(flags)?;
+        // no RWX would turn the leaves into pointers to tables, and W
+        // without R is reserved
+        let rwx = pte_flags & (ptable::PTE_READ | ptable::PTE_WRITE | ptable::PTE_EXEC);
+        if rwx == 0 || rwx & (ptable::PTE_READ | ptable::PTE_WRITE) == ptable::PTE_WRITE {
+            return Err(HALVMError::UnsupportedFlags(flags));
+        }
+        let table = table_hal_to_ptable(pgtbl);
+        if !ptable::range_is_mapped(table, virt, nbytes) {
+            return Err(HALVMError::NotMapped);
+        }
+        let res = ptable::page_protect(table, virt, nbytes, pte_flags);
+        // even a failed split may have changed some of the range
+        Self::tlb_shootdown(HartSet::All, virt, nbytes);
+        res.map_err(|_| HALVMError::FailedAllocation)
+    }
+
+    type PgtblIter = PgtblMappings;
+
+    fn pgtbl_mappings(pgtbl: &PageTable) -> PgtblMappings {
+        PgtblMappings(ptable::MappingIter::new(ptable::PageTable::new(pgtbl.addr)))
+    }
+
     fn pgtbl_remove_range(pgtbl: PageTable, virt: VirtAddress, nbytes: usize) -> Result<(), HALVMError> {
-        // TODO add logic for pruning intermediate levels. Currently
-        // there is a very slight memory buildup. It's not a leak
-        // because they will be cleaned up on free anyway, but we
-        // could free them here
-        //
-        // this leads to an unintuitive feature that removing a
-        // mapping can fail due to an out of memory error. This should
-        // never happen if all remove_range calls are on intervals
-        // that are subsets of previous insert_range calls. Allocation
-        // is only required when removing (invalidating) a range that
-        // was previously invalid at a higher level, and this function
-        // will allocate intermadiate level pages that are filled with
-        // invalid entries
-        match ptable::page_map(
-            table_hal_to_ptable(pgtbl),
-            virt,
-            0 as PhysAddress,
-            nbytes,
-            flags_hal_to_ptable(PageMapFlags::empty())?
-        ) {
-            Ok(()) => Ok(()),
-            Err(_) => Err(HALVMError::FailedAllocation),
+        let (unhooked, res) = ptable::page_unmap(table_hal_to_ptable(pgtbl), virt, nbytes);
+        // We don't know who might be using this table, so everyone
+        // has to forget the old mappings. That includes any cached
+        // walks through the tables we are about to free
+        Self::tlb_shootdown(HartSet::All, virt, nbytes);
+        for page in unhooked {
+            if pfree(page).is_err() {
+                log!(Warning, "Failed to free a pruned page table level");
+            }
         }
+        // this can only fail splitting a superpage
+        res.map_err(|_| HALVMError::FailedAllocation)
     }
 
-    fn pgtbl_free(_pgtbl: PageTable) {
-        todo!("Reuse deep copy code to collect all the pages that have been allocated in the past as intermediate levels of this pagetable.")
+    fn pgtbl_free(pgtbl: PageTable) {
+        // only the tables, the mapped pages belong to whoever mapped
+        // them
+        if ptable::free_tree(table_hal_to_ptable(pgtbl)).is_err() {
+            log!(Warning, "Failed to free part of a page table");
+        }
     }
 
     fn pgtbl_swap(pgtbl: &PageTable) {
-        let mut base_addr = pgtbl.addr as usize;
-        base_addr = (base_addr >> PAGE_OFFSET) | (8 << 60); // base addr + 39bit addressing
+        let base_addr = ptable::satp_for(pgtbl.addr);
         unsafe {
             asm!(
                 "sfence.vma zero, zero",
@@ -379,143 +576,225 @@ impl HALVM for HAL {
             );
         }
     }
-}
-
-// -------------------------------------------------------------------
-mod plic;
 
-fn read_scause() -> usize {
-    unsafe {
-        let out: usize;
-        asm!(
-            "csrr {out}, scause",
-            out = out(reg) out
-        );
-        out
+    fn pgtbl_prepare_switch(pgtbl: &PageTable, asid: &mut Asid) -> usize {
+        asid::satp_for_switch(pgtbl.addr, asid)
     }
 }
 
-fn read_stval() -> usize {
-    unsafe {
-        let out: usize;
-        asm!(
-            "csrr {out}, stval",
-            out = out(reg) out
-        );
-        out
-    }
-}
+// -------------------------------------------------------------------
+mod plic;
+mod trapframe;
+use trapframe::TrapFrame;
 
 /// These are the cause numbers for the regular s mode handler. I don't
 /// see any reason they need to be public.
 ///
 /// TODO how can we make these generic over 32/64 bit width?
+const SSTATUS_SIE: usize = 1 << 1;
+
 const S_EXTERN_IRQ: usize = 0x9 | ( 1 << 63);
+const S_TIMER_IRQ: usize = 0x5 | ( 1 << 63);
+const S_SOFT_IRQ: usize = 0x1 | ( 1 << 63);
+const S_INST_MISALIGNED: usize = 0x0;
+const S_INST_ACCESS_FAULT: usize = 0x1;
+const S_ILLEGAL_INST: usize = 0x2;
+const S_LOAD_MISALIGNED: usize = 0x4;
+const S_LOAD_ACCESS_FAULT: usize = 0x5;
+const S_STORE_MISALIGNED: usize = 0x6;
+const S_STORE_ACCESS_FAULT: usize = 0x7;
+const S_INST_PAGE_FAULT: usize = 0xc;
 const S_STORE_AMO_FAULT: usize = 0xf;
 const S_LOAD_PAGE_FAULT: usize = 0xd;
 
-/// Supervisor mode trap handler.
+/// The exceptions that are a process's own fault, when they come from
+/// U mode
+fn user_fault_name(cause: usize) -> Option<&'static str> {
+    match cause {
+        S_INST_MISALIGNED => Some("Misaligned instruction"),
+        S_INST_ACCESS_FAULT => Some("Instruction access fault"),
+        S_ILLEGAL_INST => Some("Illegal instruction"),
+        S_LOAD_MISALIGNED => Some("Misaligned load"),
+        S_LOAD_ACCESS_FAULT => Some("Load access fault"),
+        S_STORE_MISALIGNED => Some("Misaligned store/AMO"),
+        S_STORE_ACCESS_FAULT => Some("Store/AMO access fault"),
+        S_INST_PAGE_FAULT => Some("Instruction page fault"),
+        S_LOAD_PAGE_FAULT => Some("Load page fault"),
+        S_STORE_AMO_FAULT => Some("Store/AMO page fault"),
+        _ => None,
+    }
+}
+
+extern "C" {
+    fn trap_abandon(to: extern "C" fn() -> !, frame: *mut TrapFrame) -> !;
+}
+
+/// Give up on the process that caused a trap, and carry on with the
+/// next one. Never returns to the process.
+fn kill_faulting_process(frame: &mut TrapFrame, what: &str) -> ! {
+    log!(
+        Warning,
+        "{} in user process at pc 0x{:x}, address 0x{:x}",
+        what, frame.sepc, frame.stval
+    );
+    unsafe {
+        // the frame is on the interrupt stack, which is about to be
+        // reused, so this is the last we see of it
+        trap_abandon(process_fault_rust, frame as *mut TrapFrame)
+    }
+}
+
+/// Supervisor mode trap handler. frame lives on the interrupt stack,
+/// and is only valid for the length of the call.
 #[no_mangle]
-pub extern "C" fn s_handler() {
-    let cause = read_scause();
+pub extern "C" fn s_handler(frame: &mut TrapFrame) {
+    let cause = frame.scause;
+
+    // a bad process shouldn't take the kernel down with it
+    if frame.is_user() && !frame.is_interrupt() {
+        if let Some(what) = user_fault_name(cause) {
+            kill_faulting_process(frame, what);
+        }
+    }
+
+    let depth = hartlocal::current().trap_enter();
+    if depth > 1 && !frame.is_interrupt() {
+        log!(Warning, "Kernel fault while handling another trap, {} traps deep", depth);
+    }
 
     match cause {
         S_EXTERN_IRQ => {
             s_extern()
         },
+        S_SOFT_IRQ => {
+            // another hart wants something from us
+            ipi::handle_ipi();
+        },
+        S_TIMER_IRQ => {
+            // Timers are one shot, so turn it off. Waking an idle
+            // scheduler is all they do yet (see scheduler_loop).
+            HAL::timer_clear();
+        },
         S_STORE_AMO_FAULT => {
             // This is a write page fault (or a kind of write permission fault)
 
-            let val = read_stval();
+            let val = frame.stval;
+            trap_backtrace(frame);
 
             // We want to catch stack over/underflow specifically;
-            if val >= HAL::stacks_start() as usize &&
-                val < (HAL::stacks_end() as usize + PAGE_SIZE) {
-                    // error with the stack area. Hit a guard page
-
-                    // TODO currently we can't tell if this is an over
-                    // or an underflow, because we don't know which
-                    // stack we were on originally. Our current HAL
-                    // does not allow for known CPU ids. This could be
-                    // changed, but I like the anonymity frankly
-                    panic!("Stack over or underflow. Make sure you don't have a huge stack frame somewhere, or cut some recursion!");
-                } else {
-                    panic!("Store/AMO fault. Faulting address 0x{:x}", val);
-                }
+            if let Some(hit) = stack_guard_hit(val) {
+                panic!("{}", hit);
+            } else {
+                panic!("Store/AMO fault. Faulting address 0x{:x}", val);
+            }
         },
         S_LOAD_PAGE_FAULT => {
             // This is a read page fault
 
-            let val = read_stval();
-            panic!("Load page fault. Faulting address 0x{:x}", val);
+            let val = frame.stval;
+            trap_backtrace(frame);
+            if let Some(hit) = stack_guard_hit(val) {
+                panic!("{}", hit);
+            } else {
+                panic!("Load page fault. Faulting address 0x{:x}", val);
+            }
         },
         _ => {
             log!(
                 Warning,
-                "Uncaught supervisor mode interupt. scause: 0x{:x}",
-                cause
+                "Uncaught supervisor mode interupt. scause: 0x{:x}, sepc: 0x{:x}",
+                cause, frame.sepc
             );
+            trap_backtrace(frame);
             panic!("s_handler panic")
         }
     }
+    hartlocal::current().trap_exit();
 }
 
-/// Called when we get a S mode external interupt. Probably UART input
-/// or virtio.
-fn s_extern() {
-    let irq = unsafe {
-        plic::PLIC.get().expect("PLIC not initialized!").claim()
+/// A kernel fault in one of the guard pages around the per hart
+/// stacks
+struct StackGuardHit {
+    addr: usize,
+    /// the hart we were on, from tp
+    hart: usize,
+    /// the stack whose guard page was hit, and its usable range
+    stack: usize,
+    range: (usize, usize),
+    /// None if it was another hart's stack, which is neither
+    overflow: Option<bool>,
+    pid: Option<usize>,
+}
+
+/// Whether addr is in a kernel stack guard page, and if so whose. The
+/// stack area is cut into MAX_HARTS equal slots counting down from
+/// the top, like smodestart.s does, each with its guard page at the
+/// bottom. The guard under a stack is also right above the next one
+/// down, and the page past the top is the guard above stack 0, so the
+/// hart we are on decides if it was an over or an underflow.
+fn stack_guard_hit(addr: usize) -> Option<StackGuardHit> {
+    let start = HAL::stacks_start() as usize;
+    let top = HAL::stacks_end() as usize;
+    let slot_size = (top - start) / HAL::MAX_HARTS;
+    if addr < start || addr >= top + PAGE_SIZE {
+        return None;
+    }
+
+    let hart = hart_id();
+    let (stack, overflow) = if addr >= top {
+        (0, if hart == 0 { Some(false) } else { None })
+    } else {
+        let below = (top - addr) / slot_size;
+        let guard = top - (below + 1) * slot_size;
+        if addr >= guard + PAGE_SIZE {
+            // mapped stack, so not ours to explain
+            return None;
+        }
+        if hart == below {
+            (below, Some(true))
+        } else if hart == below + 1 {
+            (hart, Some(false))
+        } else {
+            (below, None)
+        }
     };
+    let stack_top = top - stack * slot_size;
+    Some(StackGuardHit {
+        addr,
+        hart,
+        stack,
+        range: (stack_top - slot_size + PAGE_SIZE, stack_top),
+        overflow,
+        pid: hartlocal::current().current_process_id(),
+    })
+}
 
-    const UART_IRQ: u32 = plic::UART_IRQ as u32;
-    const VIRTIO_IRQ: u32 = plic::VIRTIO_IRQ as u32;
-    match irq {
-        0 => {
-            // reserved for "No interrupt" according to the
-            // cookbook. Just chill I guess, I don't think we need to
-            // complete it
-        }
-        UART_IRQ => {
-            // I intentionally don't hold the lock here to
-            // allow printing. Normally we shouldn't print
-            // here
-
-
-            panic!("Unexpected UART input interrupt. Are you not using opensbi?");
-            /*
-            let input = unsafe {
-                match uart::WRITER.lock().get() {
-                    Some(i) => i,
-                    None => {
-                        // spurious irq? just exit early
-                        plic::PLIC.get().unwrap().complete(irq);
-                        return
-                    }
-                }
-            };
-            log!(Info, "Got UART input: {}",
-                 char::from_u32(input as u32).expect(
-                     "Illformed UART input character!"
-                 ));
-            log!(Debug, "Ignored uart input. Consider buffering inside HAL");
-            unsafe {
-                plic::PLIC.get().unwrap().complete(irq)
-            };
-             */
-        },
-        VIRTIO_IRQ => {
-            todo!("virtio interrupts");
-        //     virtio::virtio_blk_intr();
-        //     unsafe {
-        //         plic::PLIC.get().unwrap().complete(irq)
-        //     };
-        },
-        _ => {
-            panic!("Uncaught PLIC exception.")
+impl core::fmt::Display for StackGuardHit {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.overflow {
+            Some(true) => write!(f, "Stack overflow on hart {}", self.hart)?,
+            Some(false) => write!(f, "Stack underflow on hart {}", self.hart)?,
+            None => write!(f, "Hart {} hit the stack guard of hart {}", self.hart, self.stack)?,
+        }
+        write!(
+            f,
+            " (stack 0x{:x}..0x{:x}, faulting address 0x{:x}",
+            self.range.0, self.range.1, self.addr
+        )?;
+        match self.pid {
+            Some(pid) => write!(f, ", process {})", pid)?,
+            None => write!(f, ", no process)")?,
         }
+        write!(f, ". Make sure you don't have a huge stack frame somewhere, or cut some recursion!")
     }
 }
 
+/// Called when we get a S mode external interupt. Whoever registered
+/// the source with register_irq deals with it.
+fn s_extern() {
+    plic::dispatch();
+}
+
 extern "C" {
     pub fn __mtrapvec();
     pub fn __strapvec();
@@ -523,6 +802,8 @@ extern "C" {
 
 /// Ideally general handler init for riscv
 impl HALIntExc for HAL {
+    type TrapFrame = TrapFrame;
+
     fn handler_setup() {
         log!(Error, "WE DON'T HAVE UART ACCESS AND SIE IS NOT SET CURRENTLY!!!");
         unsafe {
@@ -534,42 +815,287 @@ impl HALIntExc for HAL {
             );
         }
         plic::global_init();
+        plic::local_init();
+        serial::irq_setup();
+    }
+
+    fn register_irq(irq: usize, priority: u32, handler: IrqHandler) -> Result<(), HALIrqError> {
+        plic::register_irq(irq, priority, handler)
+    }
+
+    fn unregister_irq(irq: usize) -> Result<(), HALIrqError> {
+        plic::unregister_irq(irq)
+    }
+
+    fn irq_set_harts(irq: usize, harts: HartSet) -> Result<(), HALIrqError> {
+        plic::set_irq_harts(irq, harts)
+    }
+
+    fn interrupts_enabled() -> bool {
+        let sstatus: usize;
+        unsafe {
+            asm!(
+                "csrr {out}, sstatus",
+                out = out(reg) sstatus
+            );
+        }
+        sstatus & SSTATUS_SIE != 0
+    }
+
+    fn interrupts_off() -> bool {
+        let prev: usize;
+        unsafe {
+            asm!(
+                "csrrc {prev}, sstatus, {sie}",
+                sie = in(reg) SSTATUS_SIE,
+                prev = out(reg) prev
+            );
+        }
+        prev & SSTATUS_SIE != 0
+    }
+
+    fn interrupts_on() {
+        unsafe {
+            asm!(
+                "csrs sstatus, {sie}",
+                sie = in(reg) SSTATUS_SIE
+            );
+        }
+    }
+
+    fn wait_for_interrupt() {
+        let was_on = Self::interrupts_enabled();
+        Self::interrupts_on();
+        unsafe {
+            asm!("wfi");
+        }
+        if !was_on {
+            Self::interrupts_off();
+        }
     }
 }
 
 // -------------------------------------------------------------------
 
+/// The id of the hart we are running on, from its HartLocal
+pub fn hart_id() -> usize {
+    hartlocal::current().hart_id()
+}
+
+/// Index into the discovered hart ids of the next hart for wake_one
+/// to try
+static NEXT_WAKE: AtomicUsize = AtomicUsize::new(0);
+
+type HartStart = Box<dyn Fn() -> ! + Send>;
+
+extern "C" {
+    fn _secondary_entry();
+}
+
+/// Rust entry for harts started by wake_one, on their own stack. We
+/// are in S mode with paging off and interrupts masked.
+#[no_mangle]
+extern "C" fn secondary_main(_hartid: usize, start: *mut HartStart) -> ! {
+    // The same per hart setup as the boot hart gets from global_setup
+    unsafe {
+        asm!(
+            "la {hold}, __strapvec",
+            "csrw stvec, {hold}",
+            hold = out(reg) _,
+        );
+    }
+    HAL::timer_clear();
+    unsafe {
+        asm!(
+            "csrs sie, {stie}",
+            stie = in(reg) SIE_STIE
+        );
+    }
+    ipi::local_init();
+    plic::local_init();
+
+    // This never returns, so this is never freed. That's fine, it's
+    // once per hart
+    let start = unsafe { &*start };
+    start()
+}
+
 impl HALCPU for HAL {
     fn isolate() {
         // This is valid to be empty, as opensbi only starts a single
         // CPU on coldboot, see docs in hal.rs
     }
 
-    fn wake_one<F: Fn() -> !>(_start: F) -> Result<(), HALCPUError> {
-        todo!("If you haven't done CPU number discovery, you should do that first.")
+    fn wake_one<F: Fn() -> ! + Send + 'static>(start: F) -> Result<(), HALCPUError> {
+        let machine = discover::machine();
+        loop {
+            let index = NEXT_WAKE.fetch_add(1, Ordering::AcqRel);
+            if index >= machine.nhart {
+                return Err(HALCPUError::OutOfCPU);
+            }
+            let hart = machine.hart_ids[index];
+            if hart == machine.boot_hart {
+                continue;
+            }
+
+            // double box so it's a thin pointer through opensbi
+            let start: *mut HartStart = Box::into_raw(Box::new(Box::new(start)));
+            let (err, _) = _opensbi_call(
+                HSM_EID as usize,
+                0,              // hart_start
+                hart,
+                _secondary_entry as usize,
+                start as usize,
+                0
+            );
+            return match err {
+                SBI_SUCCESS => Ok(()),
+                _ => {
+                    // it will never run it, so we get it back
+                    drop(unsafe { Box::from_raw(start) });
+                    log!(Warning, "Opensbi failed to start hart {}: error {}", hart, err);
+                    Err(HALCPUError::WakeFailed)
+                }
+            };
+        }
+    }
+
+    fn hart_id() -> usize {
+        hart_id()
+    }
+
+    fn hart_local() -> &'static HartLocal {
+        hartlocal::current()
     }
 }
 
 // -------------------------------------------------------------------
+mod ipi;
+
+impl HALIPI for HAL {
+    fn ipi_setup() {
+        ipi::global_init();
+        ipi::local_init();
+    }
+
+    fn tlb_shootdown(harts: HartSet, virt: VirtAddress, nbytes: usize) {
+        ipi::sfence(harts, Some((virt, nbytes)));
+    }
+
+    fn tlb_shootdown_all(harts: HartSet) {
+        ipi::sfence(harts, None);
+    }
+
+    fn run_on<F: Fn() + Send + Sync + 'static>(harts: HartSet, f: F) {
+        ipi::run_on(harts, Arc::new(f));
+    }
+
+    fn halt_others() {
+        ipi::halt_others();
+    }
+
+    fn ipi_poll() {
+        ipi::poll();
+    }
+}
+
+// -------------------------------------------------------------------
+
+const SRST_SHUTDOWN: u32 = 0x0;
+const SRST_COLD_REBOOT: u32 = 0x1;
+const SRST_REASON_NONE: u32 = 0x0;
+const SRST_REASON_FAILURE: u32 = 0x1;
+
+/// What the sifive_test device takes to end qemu with a failure. The
+/// exit status goes in the top 16 bits
+const TEST_FAIL: u32 = 0x3333;
+
+/// Ask opensbi to reset the system. Only returns if it couldn't.
+fn opensbi_system_reset(kind: u32, reason: u32) {
+    if !opensbi_probe(SRST_EID) {
+        return;
+    }
+    let (err, _) = opensbi_call(SRST_EID, 0, kind, reason, 0, 0);
+    log!(Error, "Opensbi failed a system reset with error code {}", err);
+}
+
+/// Last resort when nothing will turn the machine off for us
+fn halt_forever() -> ! {
+    HAL::halt_others();
+    loop {
+        unsafe { asm!("wfi"); }
+    }
+}
+
+impl HALPower for HAL {
+    fn shutdown(code: u32) -> ! {
+        let _ = Self::serial_flush();
+        // opensbi's qemu drivers (sifive_test and syscon-poweroff)
+        // ignore the reason and always exit 0, so a failure goes to
+        // the test device ourselves
+        if code != 0 {
+            if let Some(dev) = discover::poweroff_device() {
+                unsafe {
+                    (dev.base as *mut u32).write_volatile(TEST_FAIL | ((code & 0xffff) << 16));
+                }
+            }
+        }
+        // no test device, so the reason is the best we can do, for
+        // firmware that looks at it
+        let reason = if code == 0 {
+            SRST_REASON_NONE
+        } else {
+            SRST_REASON_FAILURE
+        };
+        opensbi_system_reset(SRST_SHUTDOWN, reason);
+        // older opensbi only has the legacy call, which can't tell
+        // success from failure
+        let _ = opensbi_call(LEGACY_SHUTDOWN_EID, 0, 0, 0, 0, 0);
+        log!(Error, "Could not shut down, halting instead");
+        halt_forever()
+    }
+
+    fn reboot() -> ! {
+        let _ = Self::serial_flush();
+        opensbi_system_reset(SRST_COLD_REBOOT, SRST_REASON_NONE);
+        log!(Error, "Could not reboot, halting instead");
+        halt_forever()
+    }
+}
+
+// -------------------------------------------------------------------
+mod discover;
+
 impl HALDiscover for HAL {
     fn discover_setup() {
-        log!(Error, "WE ARE USING HARDCODED HARDWARE DISCOVERY!!!");
+        discover::discover();
     }
 
-    const NHART: usize = 2;
+    // This must agree with the stack space in the linkerscript
+    const MAX_HARTS: usize = 8;
 
-    const DRAM_BASE: *mut usize = 0x80000000 as *mut usize;
+    fn nhart() -> usize {
+        discover::machine().nhart
+    }
+
+    fn dram_base() -> *mut usize {
+        Self::memory_start()
+    }
+
+    fn memory_regions() -> &'static [MemoryRegion] {
+        discover::machine().memory()
+    }
+
+    fn mmio_devices(kind: DeviceKind) -> &'static [MmioDevice] {
+        discover::machine().devices(kind)
+    }
 }
 
 // -------------------------------------------------------------------
-// These are supplied by the linkerscript at the moment and is thus
-// stateless, fully compiletime, and does not require device tree
-// parsing or any other kind of discovery. This method does assume
-// that the memory placement is known at compile time (seems
-// reasonable for a kernel), and that the linkerscript assumptions
-// about memory are accurate. Thus at the moment no one compilation
-// will work for multiple boards. But at the moment it's a good
-// middlground.
+// These are supplied by the linkerscript and are thus stateless and
+// fully compiletime. The exception is the extent of main memory,
+// which comes from discovery when it is available, and falls back on
+// the linkerscript's guess otherwise.
 
 // This ugly two macro setup is gross, but I can't use just one to do
 // both cause each invocation would need to strattle both. And you
@@ -612,6 +1138,8 @@ linker_var!(_stacks_end);
 linker_var!(_intstacks_start);
 linker_var!(_intstacks_end);
 
+linker_var!(_heap_start);
+
 linker_var!(_memory_start);
 linker_var!(_memory_end);
 
@@ -638,26 +1166,125 @@ impl HALSections for HAL {
     trait_wrapper!(_intstacks_start, intstacks_start);
     trait_wrapper!(_intstacks_end, intstacks_end);
 
-    trait_wrapper!(_memory_start, memory_start);
-    trait_wrapper!(_memory_end, memory_end);
+    trait_wrapper!(_heap_start, heap_start);
+
+    fn memory_start() -> *mut usize {
+        match discover::kernel_region() {
+            Some(r) => r.start as *mut usize,
+            None => linker_memory_start(),
+        }
+    }
+
+    fn memory_end() -> *mut usize {
+        match discover::kernel_region() {
+            Some(r) => r.end() as *mut usize,
+            None => linker_memory_end(),
+        }
+    }
+}
+
+fn linker_memory_start() -> *mut usize {
+    addr_of_mut!(_memory_start)
+}
+
+fn linker_memory_end() -> *mut usize {
+    addr_of_mut!(_memory_end)
+}
+
+// -------------------------------------------------------------------
+// Backtraces, by following the frame pointer chain. With frame
+// pointers s0 is the sp on entry, with the return address just under
+// it and the caller's s0 under that.
+
+/// Whether fp could be a frame on one of the kernel's stacks
+fn plausible_frame(fp: usize) -> bool {
+    let on = |start: *mut usize, end: *mut usize| {
+        fp > start as usize + 16 && fp <= end as usize
+    };
+    fp.is_multiple_of(8) &&
+        (on(HAL::stacks_start(), HAL::stacks_end()) ||
+         on(HAL::intstacks_start(), HAL::intstacks_end()))
+}
+
+/// Walk the frame chain from fp into frames, giving how many we got
+fn walk_frames(mut fp: usize, frames: &mut [usize]) -> usize {
+    let mut n = 0;
+    while n < frames.len() && plausible_frame(fp) {
+        let (ra, prev) = unsafe {
+            (*((fp - 8) as *const usize), *((fp - 16) as *const usize))
+        };
+        if ra == 0 {
+            break;
+        }
+        frames[n] = ra;
+        n += 1;
+        // callers are further up the same stack, except where a trap
+        // moved us onto the interrupt stack, which plausible_frame
+        // checks anyway
+        if prev == fp {
+            break;
+        }
+        fp = prev;
+    }
+    n
+}
+
+impl HALBacktrace for HAL {
+    #[inline(never)]
+    fn backtrace(frames: &mut [usize]) -> usize {
+        let fp: usize;
+        unsafe {
+            asm!("mv {}, s0", out(reg) fp);
+        }
+        walk_frames(fp, frames)
+    }
+}
+
+/// Print where a kernel trap came from, before we panic about it. The
+/// panic's own backtrace skips the trapping function, as it never
+/// made a call.
+fn trap_backtrace(frame: &TrapFrame) {
+    let mut frames = [0; crate::backtrace::MAX_FRAMES];
+    frames[0] = frame.sepc;
+    let n = walk_frames(frame.regs[trapframe::REG_S0], &mut frames[1..]);
+    panic_println!("trapped at:");
+    crate::backtrace::print(&frames[..n + 1]);
+}
+
+// -------------------------------------------------------------------
+// Failstate, which lives in a page after bss
+
+mod failstate;
+
+impl HALFailstate for HAL {
+    fn failstate_write(info: &PanicInfo) {
+        failstate::write(info);
+    }
+
+    fn failstate_report() {
+        failstate::report();
+    }
 }
 
 // -------------------------------------------------------------------
 mod hartlocal;
 
 impl HALSwitch for HAL {
-    type GPInfo = hartlocal::GPInfo;
+    // a0, see save_gp_regs
+    const SCALL_RETURN_OFFSET: usize = 10 * 8;
 
     fn switch_setup() {
-        hartlocal::hartlocal_info_interrupt_stack_init();
+        // Nothing left to do, each hart's HartLocal and trap scratch
+        // are set up as it comes up, see hartlocal.rs
     }
 
-    fn save_gp_info(gpi: Self::GPInfo) {
-        hartlocal::save_gp_info64(gpi);
+    fn save_process(proc: Process) {
+        hartlocal::current().set_current_process(proc);
     }
 
-    fn restore_gp_info() -> Self::GPInfo {
-        hartlocal::restore_gp_info64()
+    fn restore_process() -> Process {
+        hartlocal::current().take_current_process()
+            .expect("No process to restore on this hart!")
     }
 }
 
@@ -682,12 +1309,16 @@ pub extern "C" fn scall_rust(a0: usize, a1: usize, a2: usize, a3: usize,
 impl HALBacking for HAL {
     fn global_setup() {
         assert!(opensbi_call(BASE_EID, 0, 0, 0, 0, 0).1 == (1<<24) | (0 & 0xFF_FF_FF), "Wrong sbi version");
-        Self::serial_setup();
+        // nowhere to report this if it fails, so prints just go
+        // nowhere
+        let _ = Self::serial_setup();
+        Self::failstate_report();
+        Self::discover_setup(); // most everything else relies on this
         Self::handler_setup(); // TODO, firgure out how opensbi works with traps
         Self::sections_setup();
         Self::switch_setup();
         Self::timer_setup();
-        Self::discover_setup();
+        Self::ipi_setup();
         Self::pgtbl_setup();
     }
 }
@@ -0,0 +1,83 @@
+//! ASID allocation for the virt backing.
+//!
+//! ASIDs are handed out lazily, the first time an address space is
+//! switched to, and are never given back one at a time. When they run
+//! out we start a new generation: every ASID is free again, and every
+//! hart does a full flush before its next switch so that nothing
+//! cached for an old owner survives. An address space holding an ASID
+//! from an old generation gets a new one on its next switch.
+//!
+//! ASID 0 is the kernel page table's, and is what everything uses if
+//! the hart has no ASID bits at all.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::*;
+use crate::id::IdGenerator;
+use crate::lock::mutex::Mutex;
+
+/// How far the generation is shifted up in an Asid. Must be past the
+/// widest ASID the hardware can have (16 bits)
+const GENERATION_SHIFT: usize = 16;
+
+/// Number of ASID bits the harts have. 0 means no ASIDs
+static ASID_BITS: AtomicUsize = AtomicUsize::new(0);
+
+/// Bit n is set while hart n owes a full flush for a generation
+/// change
+static FLUSH_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+struct AsidPool {
+    generation: usize,
+    ids: IdGenerator,
+}
+
+static POOL: Mutex<AsidPool> = Mutex::new(AsidPool {
+    // 0 is an unassigned Asid
+    generation: 1,
+    // just ASID 1 until init knows how many bits there are
+    ids: IdGenerator::with_range(1, 2),
+});
+
+/// Call once, before any address space is switched to
+pub fn init(bits: usize) {
+    if bits != 0 {
+        POOL.lock().ids = IdGenerator::with_range(1, 1 << bits);
+    }
+    ASID_BITS.store(bits, Ordering::Release);
+}
+
+/// Make sure asid is valid for the current generation, and return
+/// the hardware ASID for it
+fn refresh(asid: &mut Asid) -> usize {
+    let mut pool = POOL.lock();
+    if asid.0 >> GENERATION_SHIFT != pool.generation {
+        let id = match pool.ids.try_generate() {
+            Some(id) => id,
+            None => {
+                pool.generation += 1;
+                pool.ids.clear();
+                FLUSH_PENDING.store(usize::MAX, Ordering::Release);
+                log!(Debug, "Out of ASIDs, starting generation {}", pool.generation);
+                pool.ids.generate()
+            },
+        };
+        asid.0 = (pool.generation << GENERATION_SHIFT) | id;
+    }
+    asid.0 & ((1 << GENERATION_SHIFT) - 1)
+}
+
+/// The satp for switching this hart to the table at base, tagged with
+/// asid. Does any flushing this hart owes first.
+pub fn satp_for_switch(base: PhysAddress, asid: &mut Asid) -> usize {
+    if ASID_BITS.load(Ordering::Acquire) == 0 {
+        // the switch code flushes when both sides have ASID 0
+        return ptable::satp_for(base);
+    }
+    let hw = refresh(asid);
+    let me = 1 << hart_id();
+    if FLUSH_PENDING.fetch_and(!me, Ordering::AcqRel) & me != 0 {
+        ptable::flush_tlb();
+    }
+    ptable::satp_with_asid(base, hw)
+}
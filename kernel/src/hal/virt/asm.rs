@@ -13,3 +13,8 @@ global_asm!(include_str!("asm/macro.s"));
 global_asm!(include_str!("asm/smodestart.s"));
 global_asm!(include_str!("asm/trap.s"));
 global_asm!(include_str!("asm/trampoline.s"));
+
+// without opensbi we also have to start in M mode, and stay there
+// underneath the kernel. See mmode.rs
+#[cfg(feature = "hal-virt-bare")]
+global_asm!(include_str!("asm/mmode.s"));
@@ -35,6 +35,7 @@
     sd x28, 224(sp)
     sd x29, 232(sp)
     sd x30, 240(sp)
+    sd x31, 248(sp)
 .endm
 
 .macro load_gp_regs
@@ -42,6 +43,7 @@
     ld x1, 8(sp)
     ld x2, 16(sp)
     ld x3, 24(sp)
+    ld x4, 32(sp)
     ld x5, 40(sp)
     ld x6, 48(sp)
     ld x7, 56(sp)
@@ -68,6 +70,120 @@
     ld x28, 224(sp)
     ld x29, 232(sp)
     ld x30, 240(sp)
+    ld x31, 248(sp)
 
     addi sp, sp, 256
 .endm
+
+### Install the satp in \new, leaving the one it replaces in \old.
+### Both sides are tagged with ASIDs (see asid.rs), so we only flush
+### the TLB when they have the same one, which is always the case on
+### harts without ASIDs. \tmp is clobbered
+.macro swap_satp new, old, tmp
+    csrrw \old, satp, \new
+    beq \old, \new, .Lsatp_done_\@
+    xor \tmp, \old, \new
+    slli \tmp, \tmp, 4
+    srli \tmp, \tmp, 48
+    ## just the ASID bits are left
+    bnez \tmp, .Lsatp_done_\@
+    sfence.vma x0, x0
+.Lsatp_done_\@:
+.endm
+
+### A TrapFrame (see trapframe.rs). The 32 registers, then sepc,
+### sstatus, scause and stval
+.equ TRAP_FRAME_SIZE, 288
+
+### Build a TrapFrame below sp. Expects to be on the interrupt stack,
+### with the interrupted sp in sscratch, like regular_strap
+.macro save_trap_frame
+    addi sp, sp, -TRAP_FRAME_SIZE
+
+    sd x0, 0(sp)
+    sd x1, 8(sp)
+    sd x3, 24(sp)
+    sd x4, 32(sp)
+    sd x5, 40(sp)
+    sd x6, 48(sp)
+    sd x7, 56(sp)
+    sd x8, 64(sp)
+    sd x9, 72(sp)
+    sd x10, 80(sp)
+    sd x11, 88(sp)
+    sd x12, 96(sp)
+    sd x13, 104(sp)
+    sd x14, 112(sp)
+    sd x15, 120(sp)
+    sd x16, 128(sp)
+    sd x17, 136(sp)
+    sd x18, 144(sp)
+    sd x19, 152(sp)
+    sd x20, 160(sp)
+    sd x21, 168(sp)
+    sd x22, 176(sp)
+    sd x23, 184(sp)
+    sd x24, 192(sp)
+    sd x25, 200(sp)
+    sd x26, 208(sp)
+    sd x27, 216(sp)
+    sd x28, 224(sp)
+    sd x29, 232(sp)
+    sd x30, 240(sp)
+    sd x31, 248(sp)
+
+    csrr t0, sscratch
+    sd t0, 16(sp)
+    csrr t0, sepc
+    sd t0, 256(sp)
+    csrr t0, sstatus
+    sd t0, 264(sp)
+    csrr t0, scause
+    sd t0, 272(sp)
+    csrr t0, stval
+    sd t0, 280(sp)
+.endm
+
+### Undo save_trap_frame, taking any changes made to the frame. The
+### interrupted sp goes back in sscratch, for the final swap
+.macro load_trap_frame
+    ld t0, 256(sp)
+    csrw sepc, t0
+    ld t0, 264(sp)
+    csrw sstatus, t0
+    ld t0, 16(sp)
+    csrw sscratch, t0
+
+    ld x1, 8(sp)
+    ld x3, 24(sp)
+    ld x4, 32(sp)
+    ld x6, 48(sp)
+    ld x7, 56(sp)
+    ld x8, 64(sp)
+    ld x9, 72(sp)
+    ld x10, 80(sp)
+    ld x11, 88(sp)
+    ld x12, 96(sp)
+    ld x13, 104(sp)
+    ld x14, 112(sp)
+    ld x15, 120(sp)
+    ld x16, 128(sp)
+    ld x17, 136(sp)
+    ld x18, 144(sp)
+    ld x19, 152(sp)
+    ld x20, 160(sp)
+    ld x21, 168(sp)
+    ld x22, 176(sp)
+    ld x23, 184(sp)
+    ld x24, 192(sp)
+    ld x25, 200(sp)
+    ld x26, 208(sp)
+    ld x27, 216(sp)
+    ld x28, 224(sp)
+    ld x29, 232(sp)
+    ld x30, 240(sp)
+    ld x31, 248(sp)
+    ld x5, 40(sp)
+
+    addi sp, sp, TRAP_FRAME_SIZE
+.endm
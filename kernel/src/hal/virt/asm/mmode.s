@@ -0,0 +1,58 @@
+### M mode entry and trap vector for hal-virt-bare, where there is no
+### opensbi under us. See mmode.rs for the rest.
+
+        .option norvc
+        .section .text.mentry
+        .global _mentry
+_mentry:
+        ## qemu -bios none starts every hart here, in M mode, with the
+        ## hart id in a0 and the device tree blob in a1. Those go
+        ## through to m_main untouched
+
+        ## harts past MAX_HARTS (see HALDiscover) have no stacks, so
+        ## they can't take part
+        li t0, 8
+        bgeu a0, t0, mentry_park
+
+        ## Each hart's 4 interrupt stack pages (see smodestart.s) are,
+        ## from the top: the S page, a guard, the M page and a
+        ## guard. mscratch gets the top of the M one
+        li t0, 0x4000
+        mul t1, a0, t0
+        .extern _intstacks_end
+        la t2, _intstacks_end
+        sub t2, t2, t1
+        li t0, 0x2000
+        sub sp, t2, t0
+        csrw mscratch, sp
+
+        ## let the kernel at the FPU (mstatus.FS = initial)
+        li t0, 1 << 13
+        csrs mstatus, t0
+
+        .extern m_main
+        call m_main
+mentry_park:
+        wfi
+        j mentry_park
+
+
+        ## The M mode trap vector. Everything we don't delegate lands
+        ## here, which is S mode ecalls and the M timer and software
+        ## interrupts
+        .section .text
+        .option norvc
+        .align 4
+        .global __mtrapvec
+__mtrapvec:
+        csrrw sp, mscratch, sp
+        save_gp_regs
+
+        ## m_handler gets the saved registers, and can change them
+        mv a0, sp
+        .extern m_handler
+        call m_handler
+
+        load_gp_regs
+        csrrw sp, mscratch, sp
+        mret
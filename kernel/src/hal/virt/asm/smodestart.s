@@ -7,28 +7,73 @@
         .section .text.entry
         .global _entry
 _entry:
+        ## opensbi hands us the device tree blob in a1. Stash it for
+        ## discovery (see discover.rs) before a1 gets reused
+        .extern BOOT_FDT
+        la a2, BOOT_FDT
+        sd a1, (a2)
+
+        .extern main
+        la t6, main
+        j hart_stack_setup
+
+        ## This is where harts started with the opensbi HSM extension
+        ## land (see wake_one). We get the hart id in a0 and the opaque
+        ## argument to hart_start in a1, which is passed through to
+        ## secondary_main
+        .global _secondary_entry
+_secondary_entry:
+        .extern secondary_main
+        la t6, secondary_main
+        j hart_stack_setup
+
+        ## Shared by both of the above. Takes the hart id in a0 and
+        ## where to go after in t6. a0 and a1 are left as they were
+        ## for the destination
+hart_stack_setup:
+        ## harts past MAX_HARTS (see HALDiscover) have no stacks or
+        ## HartLocal, so they can't take part. That includes the boot
+        ## hart, opensbi can pick any
+        li a4, 8
+        bgeu a0, a4, hart_park
+
         mv a3, a0
-        li a0, 0x3000           #2 page stack + guard page
-        mul a1, a3, a0          #offset by hart id
+        li a4, 0x9000           #8 page stack + guard page
+        mul a5, a3, a4          #offset by hart id
         .extern _stacks_end
         la a2, _stacks_end      # this is the top byte for hart 0
-        sub sp, a2, a1
+        sub sp, a2, a5
 
         ## We want to do a similar thing for the interupt stacks
 
-        ## we can't reuse the offset (a1) because the spacing is different
-        li a0, 0x2000           #2 page stack + guard page
-        mul a1, a3, a0          #offset by hart id
+        ## we can't reuse the offset (a5) because the spacing is different
+        li a4, 0x4000           #m and s mode pages + guard pages
+        mul a5, a3, a4          #offset by hart id
         .extern _intstacks_end
         la a2, _intstacks_end
-        sub a2, a2, a1
+        sub a2, a2, a5
         ## int stack base in a2 now.
 
-        ## put half of the initial contents of the sscratch stack in
-        ## now, namely the kernel stack base addr for this hart
+        ## the sscratch stack holds one word, the address of this
+        ## hart's HartLocal, which hart_local_setup fills in
         addi a2, a2, -8
-        sd sp, (a2)
         csrw sscratch, a2 # Write per hart sscratch pad
 
-        .extern main
-        j main
+        ## Point tp at this hart's HartLocal (see hartlocal.rs), with
+        ## the kernel stack top for the trap path. Save what the
+        ## destination needs across the call
+        mv s1, a0
+        mv s2, a1
+        mv s3, t6
+        mv a1, sp
+        .extern hart_local_setup
+        call hart_local_setup
+        mv a0, s1
+        mv a1, s2
+
+        jr s3
+
+        ## Nowhere to report this from, there's no console yet
+hart_park:
+        wfi
+        j hart_park
@@ -3,7 +3,7 @@
         ## first thing on a switch out
 
         ## jump into a process that hasn't been run yet
-        ## pc in a0, new base pagetable addr in a1, sp in a2
+        ## pc in a0, new satp in a1, sp in a2
         ##
         ## we don't need to worry about saving registers, as this is a
         ## non-returning function call
@@ -12,27 +12,19 @@ process_start_asm:
         csrw sepc, a0
         ## return to the process on sret
 
-        ## before swapping anything, we need to save the gp back to
-        ## the top of the sscratch stack
-        csrr a0, sscratch
-        sd gp, (a0)
-
         mv sp, a2
         ## get onto the process stack, we will restore kernel stack
         ## with sscratch later
 
-        li a0, 1
-        sll a0, a0, 63
-        ## top bit
-        srl a1, a1, 12
-        or a1, a1, a0
-        ## top bit mode and PPN
-
-        sfence.vma x0, x0
-        csrw satp, a1
-        sfence.vma x0, x0
+        ## a1 is the full satp, ASID included, and pgtbl_prepare_switch
+        ## has already done any flushing we owe
+        swap_satp a1, a0, a3
         ## swap tables
 
+        ## tp is the process's own from here on, and shouldn't give
+        ## away where our HartLocal is
+        mv tp, zero
+
         sret
         ## enter the process with usermode and pc/satp
         ##
@@ -42,30 +34,15 @@ process_start_asm:
 ### ------------------------------------------------------------------
 
         ## jump into a process that has been run before
-        ## takes pc in a0, new base pt in a1, and new sp in a2
+        ## takes pc in a0, new satp in a1, and new sp in a2
         .global process_resume_asm
 process_resume_asm:
         csrw sepc, a0
         ## return to the process on sret
 
-
-        ## before we swap page tables, we need to save the gp info to
-        ## a place we can restore to later
-        ##
-        ## Specifically the top of the sscratch stack
-        csrr a0, sscratch
-        sd gp, (a0)
-
-        li a0, 1
-        sll a0, a0, 63
-        ## top bit
-        srl a1, a1, 12
-        or a1, a1, a0
-        ## top bit mode and PPN
-
-        sfence.vma x0, x0
-        csrw satp, a1
-        sfence.vma x0, x0
+        ## a1 is the full satp, ASID included, and pgtbl_prepare_switch
+        ## has already done any flushing we owe
+        swap_satp a1, a0, a3
         ## swap tables
 
         mv sp, a2
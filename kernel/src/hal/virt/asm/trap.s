@@ -4,22 +4,8 @@
 ### / disappears after the trap exits
 
 
-##         .section .text
-##         ## This is the machine mode trap vector(not really). It exists
-##         ## to get us into the rust handler
-##         .option norvc
-##         .align 4
-##         .global __mtrapvec
-## __mtrapvec:
-##         csrrw sp, mscratch, sp
-##         save_gp_regs
-
-##         .extern m_handler
-##         call m_handler
-
-##         load_gp_regs
-##         csrrw sp, mscratch, sp
-##         mret
+### The machine mode trap vector is in mmode.s, and only exists for
+### hal-virt-bare
 
 ### ------------------------------------------------------------------
 ###
@@ -50,39 +36,48 @@ __strapvec:
 ### This is on the interrupt stack
 regular_strap:
         ld t0, -8(sp)
-        save_gp_regs
+        save_trap_frame
 
-        ## load kernel page table
-        ld t1, 264(sp)          #256 + 8
-
-        li a0, 1
-        sll a0, a0, 63
-        ## top bit
-        srl t1, t1, 12
-        or t1, t1, a0
-        ## top bit mode and PPN
-
-        sfence.vma x0, x0
-        csrrw s1, satp, t1
-        sfence.vma x0, x0
+        ## a process has its own tp, so get ours back from the
+        ## sscratch stack (see hartlocal.rs). It is restored with the
+        ## rest of the frame
+        ld tp, TRAP_FRAME_SIZE(sp)
+
+        ## load kernel page table from the trap scratch
+        ld t1, 0(tp)
+        ## this is already the full satp, mode and PPN
+
+        swap_satp t1, s1, t2
         ## now in kernel space, note that s1 should not be distrubed
         ## by rust
 
-        ## get gp back to restore more info from later
-        ld gp, 256(sp)
-
+        ## s_handler gets the frame
+        mv a0, sp
         .extern s_handler
         call s_handler
 
-        sfence.vma x0, x0
-        csrw satp, s1
-        sfence.vma x0, x0
+        swap_satp s1, t0, t1
 
-        load_gp_regs
+        load_trap_frame
         csrrw sp, sscratch, sp
         sret
 
 
+        ## Leave a trap from U mode for good, without going back to
+        ## the process. Takes where to go in a0 and the trap frame in
+        ## a1. We end up like a syscall that left the process: on the
+        ## kernel page table (already in place) and kernel stack, with
+        ## sscratch back at the top of the interrupt stack
+        .global trap_abandon
+trap_abandon:
+        addi t0, a1, TRAP_FRAME_SIZE
+        csrw sscratch, t0
+        ## the frame sits right under the sscratch stack contents, see
+        ## below for those. tp is already ours, from regular_strap
+        ld sp, 8(tp)
+        jr a0
+
+
         ## The ecall / syscall handler is here.
         ##
         ## It follows the linux riscv calling convention for syscalls
@@ -117,10 +112,32 @@ scall_asm:
         ## stack/page table, and non-zero for the kernel stack/ page
         ## table
 
-        beqz a0, dont_change_stack
+        bnez a0, change_stack
+
+        ## staying on the sscratch stack and process page table
+dont_change_stack:
+        addi sp, sp, -8
+        ld ra, (sp)
+
+        ## tp is still the process's, and the handler may want this
+        ## hart's HartLocal. Keep theirs on the sscratch stack and use
+        ## ours, from the word the stack starts at (see hartlocal.rs).
+        ## Only here, the switch below loads ours itself
+        addi sp, sp, -8
+        sd tp, (sp)
+        ld tp, 24(sp)
+
+        jal scall_rust
+
+        ld tp, (sp)
+        addi sp, sp, 8
+
+        sret
+
 ### -=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
 ### This is the context switch
 
+change_stack:
         ## change stacks/page table here
         ld a0, (sp)
         addi sp, sp, 8
@@ -139,38 +156,29 @@ scall_asm:
         ## sscratch holds the interrupt stack
         csrr sp, sscratch
 
-        ## sscratch stack holds, from low addr to high:
+        ## the sscratch stack holds the address of this hart's
+        ## HartLocal, which goes in tp while we are in the kernel. Its
+        ## trap scratch holds, from low addr to high (see
+        ## hartlocal.rs):
         ##
-        ## the addr to restore to gp (see hartlocal.rs)
         ## the kernel page table (satp)
         ## the kernel stack (sp)
+        ld tp, (sp)
 
         ## load kernel page table
-        ld t1, 8(sp)
-
-        li t0, 1
-        sll t0, t0, 63
-        ## top bit
-        srl t1, t1, 12
-        or t1, t1, t0
-        ## top bit mode and PPN
+        ld t1, 0(tp)
+        ## this is already the full satp, mode and PPN
 
-        sfence.vma x0, x0
-        csrw satp, t1
-        sfence.vma x0, x0
+        swap_satp t1, t2, t3
 
-        ## get gp back to restore more info from later
-        ld gp, (sp)
         ## get on the main kernel stack
-        ld sp, 16(sp)
+        ld sp, 8(tp)
 
 ### -=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=-=
 ### This is the end of the context switch
 ### We are fully in kernel space now.
 ### The program pc is in a0 and the program sp is in a1
 
-dont_change_stack:
-
         addi sp, sp, -8
         ld ra, (sp)
         ## call the main handler (this should be included in any HAL
@@ -0,0 +1,16 @@
+//! Where the machines this backing runs on differ. They are all qemu
+//! riscv machines under opensbi, so the rest of the backing is
+//! shared, and exactly one of these is compiled in.
+//!
+//! Everything here is the fallback layout for when there is no device
+//! tree, plus the few things a device tree doesn't say.
+
+#[cfg(not(feature = "hal-sifive-u"))]
+mod qemu_virt;
+#[cfg(not(feature = "hal-sifive-u"))]
+pub use qemu_virt::*;
+
+#[cfg(feature = "hal-sifive-u")]
+mod sifive_u;
+#[cfg(feature = "hal-sifive-u")]
+pub use sifive_u::*;
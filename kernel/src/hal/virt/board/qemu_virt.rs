@@ -0,0 +1,36 @@
+//! The qemu 'virt' machine
+
+use crate::hal::MmioDevice;
+
+pub const NAME: &str = "qemu virt";
+
+/// Harts we assume without a device tree
+pub const HARTS: &[usize] = &[0, 1];
+
+pub const UART: MmioDevice = MmioDevice { base: 0x10000000, size: 0x100, irq: Some(10) };
+pub const PLIC: MmioDevice = MmioDevice { base: 0xc000000, size: 0x400000, irq: None };
+pub const CLINT: MmioDevice = MmioDevice { base: 0x2000000, size: 0x10000, irq: None };
+pub const RTC: Option<MmioDevice> = Some(MmioDevice { base: 0x101000, size: 0x1000, irq: Some(11) });
+pub const POWEROFF: Option<MmioDevice> = Some(MmioDevice { base: 0x100000, size: 0x1000, irq: None });
+
+const fn virtio_slot(slot: usize) -> MmioDevice {
+    MmioDevice {
+        base: 0x10001000 + slot * 0x1000,
+        size: 0x1000,
+        irq: Some(1 + slot as u32),
+    }
+}
+
+pub const VIRTIO: &[MmioDevice] = &[
+    virtio_slot(0), virtio_slot(1), virtio_slot(2), virtio_slot(3),
+    virtio_slot(4), virtio_slot(5), virtio_slot(6), virtio_slot(7),
+];
+
+/// Highest PLIC interrupt source
+pub const PLIC_NDEV: usize = 95;
+
+/// The PLIC context for a hart's S mode. Every hart has an M mode
+/// context followed by an S mode one.
+pub const fn plic_s_context(hart: usize) -> usize {
+    2 * hart + 1
+}
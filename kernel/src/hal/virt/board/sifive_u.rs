@@ -0,0 +1,34 @@
+//! The qemu 'sifive_u' machine, modeled on the HiFive Unleashed. Hart
+//! 0 is an E51 monitor core with no S mode or MMU, which opensbi keeps
+//! to itself. The U54s are harts 1 and up.
+
+use crate::hal::MmioDevice;
+
+pub const NAME: &str = "qemu sifive_u";
+
+/// Harts we assume without a device tree. Just the first U54, as
+/// that's all qemu gives us by default.
+pub const HARTS: &[usize] = &[1];
+
+/// UART0, the one opensbi and the device tree's stdout use
+pub const UART: MmioDevice = MmioDevice { base: 0x10010000, size: 0x1000, irq: Some(4) };
+pub const PLIC: MmioDevice = MmioDevice { base: 0xc000000, size: 0x4000000, irq: None };
+pub const CLINT: MmioDevice = MmioDevice { base: 0x2000000, size: 0x10000, irq: None };
+
+/// The board's RTC has no driver here, so there's no wall clock
+pub const RTC: Option<MmioDevice> = None;
+
+/// qemu's test device, not part of the real board
+pub const POWEROFF: Option<MmioDevice> = Some(MmioDevice { base: 0x100000, size: 0x1000, irq: None });
+
+/// No virtio on real hardware, so none here either
+pub const VIRTIO: &[MmioDevice] = &[];
+
+/// Highest PLIC interrupt source
+pub const PLIC_NDEV: usize = 53;
+
+/// The PLIC context for a hart's S mode. The E51 only has an M mode
+/// context (0), after which each U54 has an M then an S context.
+pub const fn plic_s_context(hart: usize) -> usize {
+    2 * hart
+}
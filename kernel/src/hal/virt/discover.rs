@@ -0,0 +1,289 @@
+//! Hardware discovery, driven by the device tree that opensbi passes
+//! us on boot. The board's layout is the fallback.
+//!
+//! Everything we care about gets copied out of the blob into a static
+//! summary during discover_setup. The blob itself lives in memory that
+//! the page pool will later hand out, so it must not be touched after
+//! vm init.
+
+use core::cell::OnceCell;
+use core::ptr::addr_of;
+
+use crate::hal::*;
+use crate::hal::fdt::Fdt;
+use super::board;
+
+/// Most of any one kind of device we keep track of. virt has 8
+/// virtio-mmio slots, and that is the most of anything
+const MAX_DEVICES: usize = 8;
+
+/// Most main memory regions we keep track of
+const MAX_MEMORY_REGIONS: usize = 4;
+
+/// What qemu has always used for the time CSR
+const DEFAULT_TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
+/// Physical address of the device tree blob, as given to _entry by
+/// opensbi in a1. Written by the asm before any rust runs, so it
+/// needs a stable name.
+#[no_mangle]
+pub static mut BOOT_FDT: usize = 0;
+
+#[derive(Clone, Copy)]
+struct DeviceTable {
+    devices: [MmioDevice; MAX_DEVICES],
+    count: usize,
+}
+
+impl DeviceTable {
+    const fn new() -> Self {
+        Self {
+            devices: [MmioDevice { base: 0, size: 0, irq: None }; MAX_DEVICES],
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, dev: MmioDevice) {
+        if self.count == MAX_DEVICES {
+            log!(Warning, "Too many devices of one kind, ignoring one at 0x{:x}", dev.base);
+            return;
+        }
+        self.devices[self.count] = dev;
+        self.count += 1;
+    }
+
+    fn as_slice(&self) -> &[MmioDevice] {
+        &self.devices[..self.count]
+    }
+}
+
+/// Everything we learned about the machine
+pub struct Machine {
+    /// The hart ids (mhartid) of usable harts, boot hart included
+    pub hart_ids: [usize; HAL::MAX_HARTS],
+    pub nhart: usize,
+    /// The hart opensbi started us on
+    pub boot_hart: usize,
+    /// Ticks per second of the time CSR
+    pub timebase_frequency: u64,
+    memory: [MemoryRegion; MAX_MEMORY_REGIONS],
+    nmemory: usize,
+    uart: DeviceTable,
+    plic: DeviceTable,
+    clint: DeviceTable,
+    virtio: DeviceTable,
+    rtc: DeviceTable,
+    poweroff: DeviceTable,
+}
+
+impl Machine {
+    const fn empty() -> Self {
+        Self {
+            hart_ids: [0; HAL::MAX_HARTS],
+            nhart: 0,
+            boot_hart: 0,
+            timebase_frequency: DEFAULT_TIMEBASE_FREQUENCY,
+            memory: [MemoryRegion { start: 0, size: 0 }; MAX_MEMORY_REGIONS],
+            nmemory: 0,
+            uart: DeviceTable::new(),
+            plic: DeviceTable::new(),
+            clint: DeviceTable::new(),
+            virtio: DeviceTable::new(),
+            rtc: DeviceTable::new(),
+            poweroff: DeviceTable::new(),
+        }
+    }
+
+    fn push_hart(&mut self, id: usize) {
+        // stacks are picked by hart id, so large ids don't fit either
+        if self.nhart == HAL::MAX_HARTS || id >= HAL::MAX_HARTS {
+            log!(Warning, "Hart {} is past the {} the kernel has room for, ignoring it", id, HAL::MAX_HARTS);
+            return;
+        }
+        self.hart_ids[self.nhart] = id;
+        self.nhart += 1;
+    }
+
+    fn push_memory(&mut self, region: MemoryRegion) {
+        if self.nmemory == MAX_MEMORY_REGIONS {
+            log!(Warning, "Too many memory regions, ignoring one at 0x{:x}", region.start);
+            return;
+        }
+        self.memory[self.nmemory] = region;
+        self.nmemory += 1;
+    }
+
+    pub fn memory(&self) -> &[MemoryRegion] {
+        &self.memory[..self.nmemory]
+    }
+
+    pub fn devices(&self, kind: DeviceKind) -> &[MmioDevice] {
+        match kind {
+            DeviceKind::Uart => self.uart.as_slice(),
+            DeviceKind::Plic => self.plic.as_slice(),
+            DeviceKind::Clint => self.clint.as_slice(),
+            DeviceKind::Virtio => self.virtio.as_slice(),
+            DeviceKind::Rtc => self.rtc.as_slice(),
+            DeviceKind::Poweroff => self.poweroff.as_slice(),
+        }
+    }
+
+    /// The memory region that holds the kernel image
+    pub fn kernel_region(&self) -> Option<&MemoryRegion> {
+        let kernel = HAL::text_start() as usize;
+        self.memory().iter().find(|r| r.contains(kernel))
+    }
+}
+
+static mut MACHINE: OnceCell<Machine> = OnceCell::new();
+
+/// The discovered memory region holding the kernel, if discovery has
+/// happened and found one. Safe to call at any point.
+pub fn kernel_region() -> Option<MemoryRegion> {
+    unsafe { MACHINE.get()?.kernel_region().copied() }
+}
+
+/// The device that powers qemu off, if discovery has happened and
+/// found one. Safe to call at any point, shutdown uses it from the
+/// panic handler.
+pub fn poweroff_device() -> Option<MmioDevice> {
+    unsafe { (*addr_of!(MACHINE)).get()?.poweroff.as_slice().first().copied() }
+}
+
+/// Get the discovery results. Panics before discover_setup.
+pub fn machine() -> &'static Machine {
+    unsafe {
+        MACHINE.get().expect("Hardware discovery used before discover_setup!")
+    }
+}
+
+/// Fill in the machine summary, from the device tree if we were
+/// given a good one, and from the historical hardcoded layout if not.
+pub fn discover() {
+    let mut machine = match unsafe { Fdt::from_ptr(BOOT_FDT as *const u8) } {
+        Ok(fdt) => {
+            let m = from_fdt(&fdt);
+            log!(Info, "Discovered {} harts and {} memory regions from the device tree at 0x{:x}",
+                 m.nhart, m.nmemory, unsafe { BOOT_FDT });
+            m
+        },
+        Err(e) => {
+            log!(Warning, "No usable device tree ({:?}), falling back to hardcoded {} layout", e, board::NAME);
+            hardcoded()
+        }
+    };
+    machine.boot_hart = super::hart_id();
+    unsafe {
+        if MACHINE.set(machine).is_err() {
+            panic!("Hardware discovery double init!");
+        }
+    }
+}
+
+fn from_fdt(fdt: &Fdt) -> Machine {
+    let mut m = Machine::empty();
+    for node in fdt.nodes() {
+        if !node.is_enabled() {
+            continue;
+        }
+        if node.depth == 1 && node.name() == "cpus" {
+            // can also be on each cpu, but qemu only puts it here. A
+            // 0 would have us dividing by it, keep the default instead
+            match node.prop_u64("timebase-frequency") {
+                Some(0) => log!(Warning, "Device tree timebase-frequency is 0, assuming {}", DEFAULT_TIMEBASE_FREQUENCY),
+                Some(freq) => m.timebase_frequency = freq,
+                None => {},
+            }
+        }
+        match node.prop_str("device_type") {
+            Some("cpu") => {
+                if let Some((id, _)) = node.reg().next() {
+                    // we need paging, so harts without an MMU (like
+                    // the sifive_u monitor core) are no use to us
+                    if !matches!(node.prop_str("mmu-type"), None | Some("riscv,none")) {
+                        m.push_hart(id as usize);
+                    } else {
+                        log!(Info, "Hart {} has no MMU, leaving it be", id);
+                    }
+                }
+                continue;
+            },
+            Some("memory") => {
+                for (start, size) in node.reg() {
+                    m.push_memory(MemoryRegion {
+                        start: start as usize,
+                        size: size as usize,
+                    });
+                }
+                continue;
+            },
+            _ => {},
+        }
+
+        let table = if node.is_compatible("ns16550a") || node.is_compatible("sifive,uart0") {
+            &mut m.uart
+        } else if node.is_compatible("riscv,plic0") || node.is_compatible("sifive,plic-1.0.0") {
+            &mut m.plic
+        } else if node.is_compatible("riscv,clint0") || node.is_compatible("sifive,clint0") {
+            &mut m.clint
+        } else if node.is_compatible("virtio,mmio") {
+            &mut m.virtio
+        } else if node.is_compatible("google,goldfish-rtc") {
+            &mut m.rtc
+        } else if node.is_compatible("sifive,test0") || node.is_compatible("sifive,test1") {
+            &mut m.poweroff
+        } else {
+            continue;
+        };
+        if let Some((base, size)) = node.reg().next() {
+            table.push(MmioDevice {
+                base: base as usize,
+                size: size as usize,
+                irq: node.first_interrupt(),
+            });
+        }
+    }
+
+    if m.nhart == 0 {
+        log!(Warning, "Device tree lists no harts, assuming just the boot hart");
+        m.push_hart(fdt.boot_cpuid() as usize);
+    }
+    if m.nmemory == 0 {
+        log!(Warning, "Device tree lists no memory, assuming the linker script's");
+        m.push_memory(linker_memory());
+    }
+    // virtio slots are listed from the top address down, put them
+    // back in slot order
+    m.virtio.devices[..m.virtio.count].sort_unstable_by_key(|d| d.base);
+    m
+}
+
+fn linker_memory() -> MemoryRegion {
+    let start = super::linker_memory_start() as usize;
+    MemoryRegion {
+        start,
+        size: super::linker_memory_end() as usize - start,
+    }
+}
+
+/// What the board looks like by default
+fn hardcoded() -> Machine {
+    let mut m = Machine::empty();
+    for &hart in board::HARTS {
+        m.push_hart(hart);
+    }
+    m.push_memory(linker_memory());
+    m.uart.push(board::UART);
+    m.plic.push(board::PLIC);
+    m.clint.push(board::CLINT);
+    for &dev in board::VIRTIO {
+        m.virtio.push(dev);
+    }
+    if let Some(rtc) = board::RTC {
+        m.rtc.push(rtc);
+    }
+    if let Some(poweroff) = board::POWEROFF {
+        m.poweroff.push(poweroff);
+    }
+    m
+}
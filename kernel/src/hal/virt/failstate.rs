@@ -0,0 +1,151 @@
+//! The panic failstate record. One page, reserved by the linker
+//! script outside of anything the loader writes, so it survives a
+//! reboot and can be read with a debugger when printing is broken:
+//!
+//! x/s (char *)&_failstate_start + 48
+//!
+//! gets the message, see FailRecord for the rest.
+
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::ptr::addr_of_mut;
+
+use super::*;
+
+/// "REEDFAIL" in memory
+const MAGIC: u64 = u64::from_le_bytes(*b"REEDFAIL");
+
+const FILE_LEN: usize = 256;
+const MESSAGE_LEN: usize = 3072;
+
+/// What's at _failstate_start. The layout is fixed so it can be read
+/// from outside.
+#[repr(C)]
+struct FailRecord {
+    magic: u64,
+    hart: u64,
+    // the trap csrs at the time of the panic, so only meaningful if
+    // it came out of a trap
+    scause: u64,
+    sepc: u64,
+    stval: u64,
+    line: u32,
+    column: u32,
+    message: [u8; MESSAGE_LEN],
+    file: [u8; FILE_LEN],
+    message_len: u32,
+    file_len: u32,
+    /// !MAGIC, so a half written record doesn't count
+    magic_end: u64,
+}
+
+const _: () = assert!(core::mem::size_of::<FailRecord>() <= PAGE_SIZE);
+
+linker_var!(_failstate_start);
+linker_var!(_failstate_end);
+
+pub fn start() -> usize {
+    addr_of_mut!(_failstate_start) as usize
+}
+
+pub fn pages() -> usize {
+    (addr_of_mut!(_failstate_end) as usize - start()) / PAGE_SIZE
+}
+
+fn record() -> *mut FailRecord {
+    start() as *mut FailRecord
+}
+
+/// Formats into a fixed buffer, dropping whatever doesn't fit
+struct Truncating<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Truncating<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// As much of bytes as is valid utf8, as truncation may have split a
+/// character
+fn as_str(bytes: &[u8]) -> &str {
+    match core::str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or(""),
+    }
+}
+
+fn read_trap_csrs() -> (u64, u64, u64) {
+    let (scause, sepc, stval): (u64, u64, u64);
+    unsafe {
+        asm!(
+            "csrr {scause}, scause",
+            "csrr {sepc}, sepc",
+            "csrr {stval}, stval",
+            scause = out(reg) scause,
+            sepc = out(reg) sepc,
+            stval = out(reg) stval,
+        );
+    }
+    (scause, sepc, stval)
+}
+
+pub fn write(info: &PanicInfo) {
+    let (scause, sepc, stval) = read_trap_csrs();
+    // Write through a raw pointer, this may be a panic on another
+    // hart, or inside the last one. Last writer wins.
+    let rec = unsafe { &mut *record() };
+    // invalidate first, so a panic partway through this leaves no
+    // half record behind
+    rec.magic = 0;
+    rec.magic_end = 0;
+    rec.hart = hart_id() as u64;
+    rec.scause = scause;
+    rec.sepc = sepc;
+    rec.stval = stval;
+
+    let mut message = Truncating { buf: &mut rec.message, len: 0 };
+    let _ = message.write_fmt(format_args!("{}", info.message()));
+    rec.message_len = message.len as u32;
+
+    let mut file = Truncating { buf: &mut rec.file, len: 0 };
+    match info.location() {
+        Some(loc) => {
+            let _ = file.write_str(loc.file());
+            rec.line = loc.line();
+            rec.column = loc.column();
+        },
+        None => {
+            rec.line = 0;
+            rec.column = 0;
+        },
+    }
+    rec.file_len = file.len as u32;
+
+    rec.magic = MAGIC;
+    rec.magic_end = !MAGIC;
+}
+
+/// Print and clear the record a previous boot left, if there is one
+pub fn report() {
+    let rec = unsafe { &mut *record() };
+    if rec.magic != MAGIC || rec.magic_end != !MAGIC {
+        return;
+    }
+    let message_len = (rec.message_len as usize).min(MESSAGE_LEN);
+    let file_len = (rec.file_len as usize).min(FILE_LEN);
+    log!(Error, "The last boot panicked on hart {}: {} at {}:{}:{}",
+         rec.hart,
+         as_str(&rec.message[..message_len]),
+         as_str(&rec.file[..file_len]), rec.line, rec.column);
+    log!(Error, "Its last trap was scause 0x{:x} sepc 0x{:x} stval 0x{:x}",
+         rec.scause, rec.sepc, rec.stval);
+    rec.magic = 0;
+    rec.magic_end = 0;
+}
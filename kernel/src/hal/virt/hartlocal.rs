@@ -1,94 +1,52 @@
-//! This is the module for the initial information restored to a hart
-//! when exiting a process back into the kernel
+//! Where each hart's HartLocal (see crate::hartlocal) lives, and how
+//! it gets found. tp points at it while we are in the kernel. A
+//! process has its own tp, so the sscratch stack holds the pointer
+//! too, for the trap path to get it back from.
 
 use core::arch::asm;
-use alloc::boxed::Box;
 
-use crate::process::Process;
+use crate::hal::*;
+use crate::hartlocal::HartLocal;
 
-// utils
+// Which trap scratch words hold what. The asm uses them by offset,
+// so these have to stay in step with trap.s
 
-/// Read and write the hart local global pointer register. In kernel
-/// space we will be using it to point to hart local kernel
-/// information including the current process to be / has been run
-pub fn write_gp(id: u64) {
-    unsafe {
-        asm!("mv gp, {}", in(reg) id);
-    }
-}
+/// The full kernel satp, paging mode included
+pub const SCRATCH_KERNEL_SATP: usize = 0;
+/// The top of this hart's kernel stack, where syscalls start on
+pub const SCRATCH_KERNEL_SP: usize = 1;
 
-pub fn read_gp() -> u64 {
-    let gp: u64;
-    unsafe {
-        asm!("mv {}, gp", out(reg) gp);
-    }
-    gp
-}
+// only used to build the array below
+#[allow(clippy::declare_interior_mutable_const)]
+const NEW: HartLocal = HartLocal::new();
 
-/// What do we need to restore when returning from a process
-pub struct GPInfo {
-    pub current_process: Process,
-    // TODO consider moving the page table and the sp from the
-    // sscratch stack to here
-    //
-    // Currently we aren't doing that becuase we need(?) that info to
-    // boostrap this, which has stronger requirements about playing
-    // nice with rust
-}
+static HARTS: [HartLocal; HAL::MAX_HARTS] = [NEW; HAL::MAX_HARTS];
 
-impl GPInfo {
-    pub fn new(current_process: Process) -> Self {
-        Self {
-            current_process,
-        }
-    }
-}
-
-/// "Consumes" the global pointer info (most importantly the process)
-/// from the rust persective, while placing a reference to it into gp
-/// for later use
-pub fn save_gp_info64(gpi: GPInfo) {
-    let ptr = Box::into_raw(Box::new(gpi));
-    // ^ If I understand correctly, this should consume gpi and give
-    // me a pointer to a heap allocated version of gpi that will
-    // outlive this function and can safely be referenced from gp
-    // later
-
-    write_gp(ptr as u64);
-}
-
-pub fn restore_gp_info64() -> GPInfo {
-    let ptr = read_gp() as *mut GPInfo;
+/// Called from smodestart.s as each hart comes up, before any other
+/// rust. We are on the hart's kernel stack, which starts at
+/// kernel_sp, paging is off, and sscratch points at the word saved
+/// for the block.
+#[no_mangle]
+extern "C" fn hart_local_setup(hart: usize, kernel_sp: usize) {
+    let block = &HARTS[hart];
+    block.init(hart);
+    block.trap_scratch[SCRATCH_KERNEL_SP].set(kernel_sp);
     unsafe {
-        let b_ptr = Box::from_raw(ptr);
-
-        Box::into_inner(b_ptr)
+        asm!(
+            "mv tp, {block}",
+            "csrr {hold}, sscratch",
+            "sd {block}, ({hold})",
+            block = in(reg) block as *const HartLocal,
+            hold = out(reg) _,
+        );
     }
 }
 
-// The point of the process _new_no_alloc is that the initial contents
-// of the GPInfo are never valid, but they are also never freed, so we
-// can't safely alloc anything here. We are out of the range of Rust's
-// drop rules, so we have to C it ourselves. It is more important to
-// have this small but of uninitialized *VERY* unsafe memory contents
-// here and have an airtight API for saving and restoring only valid
-// values than the reverse.
-
-pub fn hartlocal_info_interrupt_stack_init() {
-    // let gpi = GPInfo {
-    //     current_process: Process::_new_no_alloc(),
-    // };
-    // save_gp_info64(gpi);
+/// This hart's block, see HALCPU::hart_local
+pub fn current() -> &'static HartLocal {
+    let tp: usize;
     unsafe {
-        asm!(
-            "csrr a0, sscratch",
-            "addi a0, a0, -16", // here we reserve space for both gp and the kernel pagetable.
-            "sd zero, (a0)",
-            // This used to write gp, but this is invalid anyway, and
-            // with just null here, we can do this before we have to
-            // pull up allocation!
-            "csrw sscratch, a0",
-            out("a0") _      // clobbers
-        )
+        asm!("mv {}, tp", out(reg) tp);
+        &*(tp as *const HartLocal)
     }
 }
@@ -0,0 +1,196 @@
+//! Inter-hart communication for the virt backing, on top of the
+//! opensbi IPI and RFENCE extensions.
+//!
+//! Cross hart function calls go through a mailbox per hart. The
+//! sender queues the work and then pokes the target with a supervisor
+//! software interrupt, and the target drains its mailbox from the trap
+//! handler, or from its idle loop if it happens to be in the kernel
+//! with interrupts off.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use super::*;
+use crate::lock::irqmutex::IrqMutex;
+
+pub const IPI_EID: u32 = 0x735049;
+pub const RFENCE_EID: u32 = 0x52464E43;
+
+const SIE_SSIE: usize = 1 << 1;
+const SIP_SSIP: usize = 1 << 1;
+
+/// Past this many pages it's cheaper to just drop the whole TLB
+const LOCAL_SFENCE_MAX_PAGES: usize = 64;
+
+type IpiWork = Arc<dyn Fn() + Send + Sync>;
+
+// only used to build the array below
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_MAILBOX: IrqMutex<VecDeque<IpiWork>> = IrqMutex::new(VecDeque::new());
+/// Taken from the IPI handler, so these keep interrupts off
+static MAILBOXES: [IrqMutex<VecDeque<IpiWork>>; HAL::MAX_HARTS] = [EMPTY_MAILBOX; HAL::MAX_HARTS];
+
+/// Bit n is set once hart id n is up and can take IPIs
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+const _: () = assert!(HAL::MAX_HARTS <= usize::BITS as usize, "Hart masks don't fit in a word");
+
+/// Set when a hart panics, and everyone else should stop
+static HALTING: AtomicBool = AtomicBool::new(false);
+
+/// Whether we can use opensbi for remote fences, or have to make do
+/// with just the local hart
+static HAVE_RFENCE: AtomicBool = AtomicBool::new(false);
+
+/// Call once on the boot hart
+pub fn global_init() {
+    if !opensbi_probe(IPI_EID) {
+        panic!("Opensbi does not support the IPI extension!");
+    }
+    HAVE_RFENCE.store(opensbi_probe(RFENCE_EID), Ordering::Release);
+    if !HAVE_RFENCE.load(Ordering::Acquire) {
+        log!(Warning, "Opensbi has no RFENCE extension, TLB flushes will only be local!");
+    }
+}
+
+/// Call once on each hart, the boot hart included
+pub fn local_init() {
+    unsafe {
+        asm!(
+            "csrs sie, {ssie}",
+            ssie = in(reg) SIE_SSIE
+        );
+    }
+    ONLINE.fetch_or(1 << hart_id(), Ordering::AcqRel);
+}
+
+/// The (hart_mask, hart_mask_base) opensbi wants for a set of harts
+/// that doesn't include this one, or None if there are none.
+fn remote_mask(harts: HartSet) -> Option<(usize, usize)> {
+    let me = hart_id();
+    let online = ONLINE.load(Ordering::Acquire);
+    let mask = match harts {
+        HartSet::This => 0,
+        HartSet::One(n) if n == me => 0,
+        HartSet::One(n) => online & (1 << n),
+        HartSet::Others | HartSet::All => online & !(1 << me),
+    };
+    if mask == 0 {
+        None
+    } else {
+        Some((mask, 0))
+    }
+}
+
+fn includes_this(harts: HartSet) -> bool {
+    match harts {
+        HartSet::This | HartSet::All => true,
+        HartSet::One(n) => n == hart_id(),
+        HartSet::Others => false,
+    }
+}
+
+fn send_ipi(mask: usize, base: usize) {
+    let (err, _) = _opensbi_call(IPI_EID as usize, 0, mask, base, 0, 0);
+    if err != SBI_SUCCESS {
+        panic!("Unexpected opensbi error code sending an IPI: {}", err);
+    }
+}
+
+/// Queue f to run on some harts, and poke them.
+pub fn run_on(harts: HartSet, f: IpiWork) {
+    if let Some((mask, base)) = remote_mask(harts) {
+        for (hart, mailbox) in MAILBOXES.iter().enumerate() {
+            if mask & (1 << hart) != 0 {
+                mailbox.lock().push_back(f.clone());
+            }
+        }
+        send_ipi(mask, base);
+    }
+    if includes_this(harts) {
+        f();
+    }
+}
+
+/// Stop every other hart for good. Does not allocate or take locks,
+/// so it's fine to use while panicking.
+pub fn halt_others() {
+    HALTING.store(true, Ordering::Release);
+    if let Some((mask, base)) = remote_mask(HartSet::Others) {
+        let _ = _opensbi_call(IPI_EID as usize, 0, mask, base, 0, 0);
+    }
+}
+
+/// Handle a supervisor software interrupt
+pub fn handle_ipi() {
+    unsafe {
+        asm!(
+            "csrc sip, {ssip}",
+            ssip = in(reg) SIP_SSIP
+        );
+    }
+    poll();
+}
+
+/// Run everything waiting in this hart's mailbox. Also called from
+/// places that wait with interrupts off, so they don't miss anything.
+pub fn poll() {
+    if HALTING.load(Ordering::Acquire) {
+        ONLINE.fetch_and(!(1 << hart_id()), Ordering::AcqRel);
+        loop {
+            unsafe { asm!("wfi"); }
+        }
+    }
+    loop {
+        // don't hold the lock while running, the work might want to
+        // send more
+        let next = MAILBOXES[hart_id()].lock().pop_front();
+        match next {
+            Some(work) => work(),
+            None => break,
+        }
+    }
+}
+
+/// Invalidate the translations for some range on some harts. Passing
+/// None for the range drops every translation.
+pub fn sfence(harts: HartSet, range: Option<(VirtAddress, usize)>) {
+    let (start, size) = match range {
+        Some((virt, nbytes)) => (virt as usize, nbytes),
+        None => (0, usize::MAX),
+    };
+    if HAVE_RFENCE.load(Ordering::Acquire) {
+        if let Some((mask, base)) = remote_mask(harts) {
+            // this one waits for the remote harts to finish
+            let (err, _) = _opensbi_call(RFENCE_EID as usize, 1, mask, base, start, size);
+            if err != SBI_SUCCESS {
+                panic!("Unexpected opensbi error code on a remote fence: {}", err);
+            }
+        }
+    }
+    if includes_this(harts) {
+        local_sfence(range);
+    }
+}
+
+fn local_sfence(range: Option<(VirtAddress, usize)>) {
+    match range {
+        Some((_, nbytes)) if nbytes > LOCAL_SFENCE_MAX_PAGES * PAGE_SIZE => {
+            local_sfence(None);
+        },
+        None => unsafe {
+            asm!("sfence.vma zero, zero");
+        },
+        Some((virt, nbytes)) => {
+            let mut page = (virt as usize) & !(PAGE_SIZE - 1);
+            let end = (virt as usize).saturating_add(nbytes);
+            while page < end {
+                unsafe {
+                    asm!("sfence.vma {page}, zero", page = in(reg) page);
+                }
+                page += PAGE_SIZE;
+            }
+        },
+    }
+}
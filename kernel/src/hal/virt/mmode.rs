@@ -0,0 +1,414 @@
+//! M mode for hal-virt-bare, where there is no opensbi and qemu
+//! (-bios none) starts every hart in M mode at _mentry (asm/mmode.s).
+//!
+//! We do the M mode setup the rest of the backing expects opensbi to
+//! have done, then stay resident underneath it as a minimal SBI. The S
+//! mode code makes exactly the calls it would make to opensbi, and
+//! they land in m_handler. Only what the backing uses is here: BASE,
+//! TIME, IPI, RFENCE, HSM and SRST. Serial goes to the UART directly
+//! (see uart.rs), so there is no debug console.
+//!
+//! Nothing in here can log or panic, there's nothing under us to
+//! print with and the panic path calls back down here.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use super::*;
+
+const CLINT_BASE: usize = 0x2000000;
+const CLINT_MSIP: usize = 0x0;
+const CLINT_MTIMECMP: usize = 0x4000;
+
+/// qemu's sifive_test device, which ends qemu when written
+const TEST_BASE: usize = 0x100000;
+const TEST_FAIL: u32 = 0x3333; // exit code in the top 16 bits
+const TEST_PASS: u32 = 0x5555;
+const TEST_RESET: u32 = 0x7777;
+
+const M_SOFT_IRQ: usize = 0x3 | (1 << 63);
+const M_TIMER_IRQ: usize = 0x7 | (1 << 63);
+const S_ECALL: usize = 0x9;
+
+const MIP_SSIP: usize = 1 << 1;
+const MIP_STIP: usize = 1 << 5;
+const MIE_MSIE: usize = 1 << 3;
+const MIE_MTIE: usize = 1 << 7;
+const MSTATUS_MPP_MASK: usize = 3 << 11;
+const MSTATUS_MPP_S: usize = 1 << 11;
+
+/// Everything but ecalls from S and M goes straight to S, same as
+/// opensbi: misalignment, access faults, illegal instructions,
+/// breakpoints, U ecalls and page faults
+const MEDELEG: usize = 0xb1ff;
+/// and all the S interrupts
+const MIDELEG: usize = MIP_SSIP | MIP_STIP | (1 << 9);
+
+// HSM hart states, as the spec numbers them
+const HART_STARTED: usize = 0;
+const HART_STOPPED: usize = 1;
+const HART_START_PENDING: usize = 2;
+
+const REG_A0: usize = 10;
+const REG_A1: usize = 11;
+const REG_A2: usize = 12;
+const REG_A6: usize = 16;
+const REG_A7: usize = 17;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const STOPPED: AtomicUsize = AtomicUsize::new(HART_STOPPED);
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const CLEAR: AtomicBool = AtomicBool::new(false);
+// only used to build the arrays below
+
+static HART_STATE: [AtomicUsize; HAL::MAX_HARTS] = [STOPPED; HAL::MAX_HARTS];
+/// Where a START_PENDING hart goes, and what it gets in a1
+static START_ADDR: [AtomicUsize; HAL::MAX_HARTS] = [ZERO; HAL::MAX_HARTS];
+static START_ARG: [AtomicUsize; HAL::MAX_HARTS] = [ZERO; HAL::MAX_HARTS];
+
+/// Why a hart's M software interrupt was raised. Either or both
+static SSIP_PENDING: [AtomicBool; HAL::MAX_HARTS] = [CLEAR; HAL::MAX_HARTS];
+static FENCE_PENDING: [AtomicBool; HAL::MAX_HARTS] = [CLEAR; HAL::MAX_HARTS];
+
+/// The first hart to get here boots, the rest wait for hart_start
+static BOOT_CLAIMED: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    fn _entry();
+}
+
+fn mhartid() -> usize {
+    let id: usize;
+    unsafe {
+        asm!("csrr {}, mhartid", out(reg) id);
+    }
+    id
+}
+
+fn msip(hart: usize) -> *mut u32 {
+    (CLINT_BASE + CLINT_MSIP + 4 * hart) as *mut u32
+}
+
+fn mtimecmp(hart: usize) -> *mut u64 {
+    (CLINT_BASE + CLINT_MTIMECMP + 8 * hart) as *mut u64
+}
+
+fn sfence_all() {
+    unsafe {
+        asm!("sfence.vma zero, zero");
+    }
+}
+
+/// Write the test device, which should end qemu
+fn test_finish(val: u32) {
+    unsafe {
+        (TEST_BASE as *mut u32).write_volatile(val);
+    }
+}
+
+/// Rust entry from _mentry, on this hart's M stack
+#[no_mangle]
+extern "C" fn m_main(hartid: usize, fdt: usize) -> ! {
+    m_setup(hartid);
+    if !BOOT_CLAIMED.swap(true, Ordering::AcqRel) {
+        HART_STATE[hartid].store(HART_STARTED, Ordering::Release);
+        // just like opensbi would hand it to us
+        enter_s(_entry as unsafe extern "C" fn() as usize, hartid, fdt)
+    }
+    park(hartid)
+}
+
+fn m_setup(hartid: usize) {
+    unsafe {
+        // no timer until S mode asks for one
+        mtimecmp(hartid).write_volatile(u64::MAX);
+        msip(hartid).write_volatile(0);
+        asm!(
+            "csrw mtvec, {mtvec}",
+            "csrw medeleg, {medeleg}",
+            "csrw mideleg, {mideleg}",
+            "csrw mie, {mie}",
+            // S mode reads time and the other counters itself
+            "csrw mcounteren, {counters}",
+            // and can get at all of memory. Without a PMP entry it
+            // can't get at any
+            "csrw pmpaddr0, {all}",
+            "csrw pmpcfg0, {napot_rwx}",
+            "csrw satp, zero",
+            mtvec = in(reg) __mtrapvec as unsafe extern "C" fn() as usize,
+            medeleg = in(reg) MEDELEG,
+            mideleg = in(reg) MIDELEG,
+            mie = in(reg) MIE_MSIE,
+            counters = in(reg) 0b111,
+            all = in(reg) usize::MAX,
+            napot_rwx = in(reg) 0x1f,
+        );
+    }
+}
+
+/// mret to S mode at pc, with a0 and a1 set
+fn enter_s(pc: usize, a0: usize, a1: usize) -> ! {
+    unsafe {
+        asm!(
+            "csrc mstatus, {mpp_mask}",
+            "csrs mstatus, {mpp_s}",
+            "csrw mepc, {pc}",
+            "mret",
+            mpp_mask = in(reg) MSTATUS_MPP_MASK,
+            mpp_s = in(reg) MSTATUS_MPP_S,
+            pc = in(reg) pc,
+            in("a0") a0,
+            in("a1") a1,
+            options(noreturn)
+        );
+    }
+}
+
+/// Where harts other than the boot one wait for hart_start. M
+/// interrupts are off here, but a software interrupt still ends the
+/// wfi.
+fn park(hartid: usize) -> ! {
+    loop {
+        unsafe {
+            asm!("wfi");
+            msip(hartid).write_volatile(0);
+        }
+        if HART_STATE[hartid].load(Ordering::Acquire) == HART_START_PENDING {
+            let pc = START_ADDR[hartid].load(Ordering::Acquire);
+            let arg = START_ARG[hartid].load(Ordering::Acquire);
+            HART_STATE[hartid].store(HART_STARTED, Ordering::Release);
+            enter_s(pc, hartid, arg);
+        }
+    }
+}
+
+/// Something we never delegated or asked for. We can't report it, so
+/// stop qemu with a failure and hope someone looks.
+fn m_fatal() -> ! {
+    test_finish((1 << 16) | TEST_FAIL);
+    loop {
+        unsafe {
+            asm!("wfi");
+        }
+    }
+}
+
+/// Called from __mtrapvec with the saved registers of whatever
+/// trapped. Changes to them are what the trap returns with.
+#[no_mangle]
+extern "C" fn m_handler(regs: &mut [usize; 32]) {
+    let cause: usize;
+    unsafe {
+        asm!("csrr {}, mcause", out(reg) cause);
+    }
+    let hart = mhartid();
+
+    match cause {
+        M_TIMER_IRQ => {
+            // hand it to S, and stop it firing here until S sets
+            // another deadline
+            unsafe {
+                asm!(
+                    "csrc mie, {mtie}",
+                    "csrs mip, {stip}",
+                    mtie = in(reg) MIE_MTIE,
+                    stip = in(reg) MIP_STIP,
+                );
+            }
+        },
+        M_SOFT_IRQ => {
+            unsafe {
+                msip(hart).write_volatile(0);
+            }
+            if FENCE_PENDING[hart].swap(false, Ordering::AcqRel) {
+                sfence_all();
+            }
+            if SSIP_PENDING[hart].swap(false, Ordering::AcqRel) {
+                unsafe {
+                    asm!("csrs mip, {ssip}", ssip = in(reg) MIP_SSIP);
+                }
+            }
+        },
+        S_ECALL => {
+            let (err, val) = sbi_call(
+                regs[REG_A7], regs[REG_A6],
+                regs[REG_A0], regs[REG_A1], regs[REG_A2],
+            );
+            regs[REG_A0] = err as isize as usize;
+            regs[REG_A1] = val;
+            // past the ecall
+            unsafe {
+                asm!(
+                    "csrr {hold}, mepc",
+                    "addi {hold}, {hold}, 4",
+                    "csrw mepc, {hold}",
+                    hold = out(reg) _,
+                );
+            }
+        },
+        _ => m_fatal(),
+    }
+}
+
+fn supported(eid: usize) -> bool {
+    [BASE_EID, TIME_EID, ipi::IPI_EID, ipi::RFENCE_EID, HSM_EID, SRST_EID]
+        .iter()
+        .any(|e| *e as usize == eid)
+}
+
+/// The SBI calls we stand in for. Returns (error, value)
+fn sbi_call(eid: usize, fid: usize, a0: usize, a1: usize, a2: usize) -> (i32, usize) {
+    const BASE: usize = BASE_EID as usize;
+    const TIME: usize = TIME_EID as usize;
+    const IPI: usize = ipi::IPI_EID as usize;
+    const RFENCE: usize = ipi::RFENCE_EID as usize;
+    const HSM: usize = HSM_EID as usize;
+    const SRST: usize = SRST_EID as usize;
+    const LEGACY_SHUTDOWN: usize = LEGACY_SHUTDOWN_EID as usize;
+
+    match (eid, fid) {
+        // spec version 1.0
+        (BASE, 0) => (SBI_SUCCESS, 1 << 24),
+        (BASE, 3) => (SBI_SUCCESS, supported(a0) as usize),
+        // implementation ids and versions, and the machine ids. We
+        // have nothing useful to say
+        (BASE, 1 | 2 | 4 | 5 | 6) => (SBI_SUCCESS, 0),
+        (TIME, 0) => {
+            set_timer(a0 as u64);
+            (SBI_SUCCESS, 0)
+        },
+        (IPI, 0) => (send_ipi(a0, a1), 0),
+        // sfence.vma and sfence.vma.asid, both done as a full flush
+        (RFENCE, 1 | 2) => (remote_sfence(a0, a1), 0),
+        (HSM, 0) => (hart_start(a0, a1, a2), 0),
+        (HSM, 2) => match HART_STATE.get(a0) {
+            Some(state) => (SBI_SUCCESS, state.load(Ordering::Acquire)),
+            None => (SBI_ERR_INVALID_PARAM, 0),
+        },
+        (SRST, 0) => (system_reset(a0, a1), 0),
+        (LEGACY_SHUTDOWN, _) => {
+            test_finish(TEST_PASS);
+            (SBI_ERR_FAILED, 0)
+        },
+        _ => (SBI_ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+fn set_timer(deadline: u64) {
+    unsafe {
+        mtimecmp(mhartid()).write_volatile(deadline);
+        asm!(
+            "csrc mip, {stip}",
+            "csrs mie, {mtie}",
+            stip = in(reg) MIP_STIP,
+            mtie = in(reg) MIE_MTIE,
+        );
+    }
+}
+
+/// The started harts an SBI (hart_mask, hart_mask_base) picks out, as
+/// a mask of hart ids. A base of all ones means every hart
+fn started_harts(mask: usize, base: usize) -> Result<usize, i32> {
+    let wanted = if base == usize::MAX {
+        usize::MAX
+    } else if base >= HAL::MAX_HARTS {
+        return Err(SBI_ERR_INVALID_PARAM);
+    } else {
+        mask << base
+    };
+    let mut out = 0;
+    for (hart, state) in HART_STATE.iter().enumerate() {
+        if wanted & (1 << hart) != 0 && state.load(Ordering::Acquire) == HART_STARTED {
+            out |= 1 << hart;
+        }
+    }
+    Ok(out)
+}
+
+fn send_ipi(mask: usize, base: usize) -> i32 {
+    let harts = match started_harts(mask, base) {
+        Ok(harts) => harts,
+        Err(e) => return e,
+    };
+    for (hart, pending) in SSIP_PENDING.iter().enumerate() {
+        if harts & (1 << hart) != 0 {
+            pending.store(true, Ordering::Release);
+            unsafe {
+                msip(hart).write_volatile(1);
+            }
+        }
+    }
+    SBI_SUCCESS
+}
+
+/// Make the harts flush their TLBs, and wait for them to do it
+fn remote_sfence(mask: usize, base: usize) -> i32 {
+    let harts = match started_harts(mask, base) {
+        Ok(harts) => harts,
+        Err(e) => return e,
+    };
+    let me = mhartid();
+    for (hart, pending) in FENCE_PENDING.iter().enumerate() {
+        if hart != me && harts & (1 << hart) != 0 {
+            pending.store(true, Ordering::Release);
+            unsafe {
+                msip(hart).write_volatile(1);
+            }
+        }
+    }
+    if harts & (1 << me) != 0 {
+        sfence_all();
+    }
+    for (hart, pending) in FENCE_PENDING.iter().enumerate() {
+        if hart == me || harts & (1 << hart) == 0 {
+            continue;
+        }
+        while pending.load(Ordering::Acquire) {
+            // they might be in here waiting on us, M interrupts
+            // don't nest
+            if FENCE_PENDING[me].swap(false, Ordering::AcqRel) {
+                sfence_all();
+            }
+            core::hint::spin_loop();
+        }
+    }
+    SBI_SUCCESS
+}
+
+fn hart_start(hartid: usize, addr: usize, arg: usize) -> i32 {
+    let state = match HART_STATE.get(hartid) {
+        Some(state) => state,
+        None => return SBI_ERR_INVALID_PARAM,
+    };
+    if state.load(Ordering::Acquire) != HART_STOPPED {
+        return SBI_ERR_ALREADY_AVAILABLE;
+    }
+    START_ADDR[hartid].store(addr, Ordering::Release);
+    START_ARG[hartid].store(arg, Ordering::Release);
+    if state.compare_exchange(HART_STOPPED, HART_START_PENDING,
+                              Ordering::AcqRel, Ordering::Acquire).is_err() {
+        return SBI_ERR_ALREADY_AVAILABLE;
+    }
+    unsafe {
+        msip(hartid).write_volatile(1);
+    }
+    SBI_SUCCESS
+}
+
+fn system_reset(kind: usize, reason: usize) -> i32 {
+    match kind as u32 {
+        SRST_SHUTDOWN => {
+            if reason as u32 == SRST_REASON_NONE {
+                test_finish(TEST_PASS);
+            } else {
+                test_finish((1 << 16) | TEST_FAIL);
+            }
+        },
+        // qemu only has the one kind of reset
+        _ => test_finish(TEST_RESET),
+    }
+    // qemu should be gone by now
+    SBI_ERR_FAILED
+}
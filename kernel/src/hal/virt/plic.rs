@@ -1,7 +1,8 @@
 // PLIC device. There is usually only one. It should be locked probably.
 
 // The PLIC mediates S-Mode and M-Mode external interrupts across all Harts.
-// The interface exists at a known memory location: PLIC_BASE
+// The interface exists at a memory location we discover, or the
+// board's default
 
 // When a device asks for an interrupt, it provides its priority level.
 // If a device's interrupt IRQ-value is above a threshold, the PLIC
@@ -18,74 +19,210 @@
 
 
 use core::{cell::OnceCell, arch::asm}; // for PLIC, write once read many times
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::hal::*;
+use crate::lock::irqmutex::IrqMutex;
+use super::{board, hart_id};
 // use crate::hw::riscv;
 // use crate::hw::param::{PLIC_BASE, UART_IRQ, VIRTIO_IRQ};
 
-/// VIRTIO interrupt request number.
+/// VIRTIO interrupt request number on qemu virt.
 pub const VIRTIO_IRQ: usize = 1;
 
-/// UART interrupt request number.
+/// UART interrupt request number on qemu virt.
 pub const UART_IRQ: usize = 10;
 
-/// PLIC base address.
-pub const PLIC_BASE: usize = 0xc000000;
+/// Number of interrupt sources, counting the reserved 0. The spec
+/// allows up to 1024, it's up to the board.
+pub const NSOURCES: usize = board::PLIC_NDEV + 1;
 
-/// PLIC size in memory
-pub const PLIC_SIZE: usize = 0x400000;
-//TODO this should be a function of NHART
-
-
-// moved from riscv
-pub fn read_tp() -> u64 {
-    let tp: u64;
-    unsafe {
-        asm!("mv {}, tp", out(reg) tp);
-    }
-    tp
-}
+/// Highest priority the PLIC implements. qemu has 3 priority bits.
+pub const MAX_PRIORITY: u32 = 7;
 
 pub static mut PLIC: OnceCell<Plic> = OnceCell::new(); // all memory accesses to Plic go through here!
 
 pub struct Plic {
     base: usize,
+    size: usize,
 }
 
-/// Single-time global initialization for Plic.
-/// Sets magic number device IRQ priorities, then initializes.
-pub fn global_init() {
-    // set desired IRQ priorities non-zero (otherwise disabled).
-    // currently just for UART
-    let base_addr = PLIC_BASE as *mut u32;
+/// A registered interrupt source
+#[derive(Clone, Copy)]
+struct IrqEntry {
+    handler: IrqHandler,
+    /// bit n set if hart n should take this source
+    harts: usize,
+}
 
-    unsafe {
-        base_addr.add(UART_IRQ).write_volatile(1);
-        base_addr.add(VIRTIO_IRQ).write_volatile(1);
-    }
+/// Registered handlers, indexed by source number. Also serializes
+/// changes to the priority and enable registers.
+static REGISTRY: IrqMutex<[Option<IrqEntry>; NSOURCES]> = IrqMutex::new([None; NSOURCES]);
+
+/// Bit n is set once hart n has done local_init. Enables for harts
+/// that aren't here yet are applied when they arrive.
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+const SIE_SEIE: usize = 1 << 9;
+
+fn plic() -> &'static Plic {
+    unsafe { PLIC.get().expect("PLIC not initialized!") }
+}
+
+/// Where the PLIC is and how big, for mapping it
+pub fn region() -> (usize, usize) {
+    (plic().base, plic().size)
+}
+
+/// Single-time global initialization for Plic. Every source starts
+/// out disabled (priority 0) until someone registers it.
+pub fn global_init() {
+    let dev = match HAL::mmio_devices(DeviceKind::Plic).first() {
+        Some(dev) => *dev,
+        None => {
+            log!(Warning, "No PLIC was discovered, assuming 0x{:x}", board::PLIC.base);
+            board::PLIC
+        }
+    };
 
     // initialize PLIC
     unsafe {
-        match PLIC.set(Plic::new(PLIC_BASE)) {
+        match PLIC.set(Plic::new(dev.base, dev.size)) {
             Ok(()) => {},
             Err(_) => panic!("Plic double init!"),
         }
         assert!(PLIC.get().is_some());
     }
+
+    for irq in 1..NSOURCES {
+        plic().set_priority(irq, 0);
+    }
 }
 
-/// Local initialize Plic. Once per HART. Enables devices, sets threshold.
+/// Local initialize Plic. Once per HART. Enables the registered
+/// sources meant for this hart, sets threshold, and turns on external
+/// interrupts.
 pub fn local_init() {
+    let hart = hart_id();
+    {
+        let registry = REGISTRY.lock();
+        for (irq, entry) in registry.iter().enumerate() {
+            let on = matches!(entry, Some(e) if e.harts & (1 << hart) != 0);
+            plic().set_enabled(hart, irq, on);
+        }
+        ONLINE.fetch_or(1 << hart, Ordering::AcqRel);
+    }
 
-    //  set enable bits for this hart's S-mode
-    let bit_mask: u32 = (1 << UART_IRQ) | (1 << VIRTIO_IRQ);
+    // accept interrupts from all enabled devices with priority > 0.
+    plic().set_s_priority_threshold(hart, 0);
 
     unsafe {
-        // call the write to Plic magic locations for the enabled bits.
-        assert!(PLIC.get().is_some());
-        PLIC.get().expect("PLIC Once Cell is not written").hart_local_enable(bit_mask);
+        asm!(
+            "csrs sie, {seie}",
+            seie = in(reg) SIE_SEIE
+        );
+    }
+}
+
+fn hart_mask(harts: HartSet) -> usize {
+    let me = 1 << hart_id();
+    match harts {
+        HartSet::This => me,
+        HartSet::One(n) => 1 << n,
+        HartSet::Others => !me,
+        HartSet::All => usize::MAX,
+    }
+}
+
+fn check_irq(irq: usize) -> Result<(), HALIrqError> {
+    // 0 is reserved for "no interrupt"
+    if irq == 0 || irq >= NSOURCES {
+        Err(HALIrqError::InvalidIrq)
+    } else {
+        Ok(())
+    }
+}
 
-        // accept interrupts from all enabled devices with priority > 0.
-        PLIC.get().expect("PLIC should be initialized").set_s_priority_threshold(0);
+/// Point the enable bits for irq on every online hart at harts. Call
+/// with the registry held.
+fn apply_enables(irq: usize, harts: usize) {
+    let online = ONLINE.load(Ordering::Acquire);
+    for hart in 0..HAL::MAX_HARTS {
+        if online & (1 << hart) != 0 {
+            plic().set_enabled(hart, irq, harts & (1 << hart) != 0);
+        }
+    }
+}
+
+/// Install handler for irq and enable it on every hart at the given
+/// priority. It is called on whichever hart claims the interrupt,
+/// and the interrupt is completed once it returns.
+pub fn register_irq(irq: usize, priority: u32, handler: IrqHandler) -> Result<(), HALIrqError> {
+    check_irq(irq)?;
+    if priority == 0 || priority > MAX_PRIORITY {
+        return Err(HALIrqError::InvalidPriority);
+    }
+    let mut registry = REGISTRY.lock();
+    if registry[irq].is_some() {
+        return Err(HALIrqError::AlreadyRegistered);
+    }
+    registry[irq] = Some(IrqEntry { handler, harts: usize::MAX });
+    plic().set_priority(irq, priority);
+    apply_enables(irq, usize::MAX);
+    Ok(())
+}
+
+/// Disable irq everywhere and forget its handler
+pub fn unregister_irq(irq: usize) -> Result<(), HALIrqError> {
+    check_irq(irq)?;
+    let mut registry = REGISTRY.lock();
+    if registry[irq].take().is_none() {
+        return Err(HALIrqError::NotRegistered);
     }
+    plic().set_priority(irq, 0);
+    apply_enables(irq, 0);
+    Ok(())
+}
+
+/// Change which harts take a registered irq
+pub fn set_irq_harts(irq: usize, harts: HartSet) -> Result<(), HALIrqError> {
+    check_irq(irq)?;
+    let mask = hart_mask(harts);
+    let mut registry = REGISTRY.lock();
+    match registry[irq].as_mut() {
+        Some(entry) => entry.harts = mask,
+        None => return Err(HALIrqError::NotRegistered),
+    }
+    apply_enables(irq, mask);
+    Ok(())
+}
+
+/// Claim the pending interrupt, run its handler, and complete it.
+/// Called from the trap handler.
+pub fn dispatch() {
+    let irq = plic().claim();
+    if irq == 0 {
+        // reserved for "No interrupt" according to the
+        // cookbook. Someone else got to it first, nothing to complete
+        return;
+    }
+
+    // copy the handler out so it runs without the registry held, and
+    // can (un)register things itself
+    let handler = REGISTRY.lock()
+        .get(irq as usize)
+        .and_then(|e| e.map(|e| e.handler));
+    match handler {
+        Some(handler) => handler(irq as usize),
+        None => {
+            // nobody wants it, so stop it from coming back
+            log!(Warning, "Unregistered PLIC interrupt {}, disabling it", irq);
+            if (irq as usize) < NSOURCES {
+                let _registry = REGISTRY.lock();
+                plic().set_priority(irq as usize, 0);
+            }
+        }
+    }
+    plic().complete(irq);
 }
 
 // currently stolen directly from xv6-riscv
@@ -96,75 +233,79 @@ pub fn local_init() {
 // not the answer, as PLICs can and should be used in parallel by
 // multiple harts.
 
+/// Per context registers. Which context is a hart's S mode is up to
+/// the board.
+const ENABLE_OFFSET: usize = 0x2000;
+const ENABLE_STEP: usize = 0x80;
+const CONTEXT_OFFSET: usize = 0x200000;
+const CONTEXT_STEP: usize = 0x1000;
+const CLAIM: usize = 4; // 4 bytes after threshold
+
 /// new makes Plic at a specific place with specific interrupts enabled.
 impl Plic {
-    fn new(base: usize) -> Self {
+    fn new(base: usize, size: usize) -> Self {
         // new Plic at base
         let out = Plic {
             base,
+            size,
         };
 
         out
     }
 
-    /// take a Plic and a threshold, set threshold at a magic location
-    /// known explicitly per hart: (RAW_OFFSET + hart * RAW_STEP).
-    fn set_s_priority_threshold(&self, threshold: u32) {
-        let addr = self.base as *mut u32;
-        let hart = read_tp() as usize;
-
-        const RAW_OFFSET: usize = 0x201000;
-        const RAW_STEP: usize = 0x2000;
+    /// the threshold register of a hart's S mode context, claim and
+    /// complete are right after it
+    fn context_reg(&self, hart: usize) -> *mut u32 {
+        let offset = CONTEXT_OFFSET + board::plic_s_context(hart) * CONTEXT_STEP;
+        (self.base + offset) as *mut u32
+    }
 
-        let final_offset = (RAW_OFFSET + (hart * RAW_STEP))/4; // / 4?
+    /// take a Plic and a threshold, set threshold for the hart's S mode
+    fn set_s_priority_threshold(&self, hart: usize, threshold: u32) {
         unsafe {
-            addr.add(final_offset).write_volatile(threshold);
+            self.context_reg(hart).write_volatile(threshold);
         }
     }
 
-    /// enables interrupts from devices by non-zero bits in bit-mask
-    pub fn hart_local_enable(&self, bit_mask: u32) {
+    /// set the priority of a source. 0 disables it on every hart
+    fn set_priority(&self, irq: usize, priority: u32) {
         let addr = self.base as *mut u32;
-        let hart = read_tp() as usize;
-
-        const RAW_OFFSET: usize = 0x2080;
-        const RAW_STEP: usize = 0x100;
+        unsafe {
+            addr.add(irq).write_volatile(priority);
+        }
+    }
 
-        let final_offset = (RAW_OFFSET + (hart * RAW_STEP))/4; //ASK: /4?
+    /// turn a source on or off for a hart's S-mode. The enable bits
+    /// are packed 32 to a word, so this is a read-modify-write and
+    /// callers need to hold the registry
+    fn set_enabled(&self, hart: usize, irq: usize, on: bool) {
+        let offset = ENABLE_OFFSET + board::plic_s_context(hart) * ENABLE_STEP;
+        let addr = (self.base + offset) as *mut u32;
+        let bit = 1 << (irq % 32);
 
         unsafe {
-            addr.add(final_offset).write_volatile(bit_mask); //ASK: length?
+            let word = addr.add(irq / 32).read_volatile();
+            let word = if on { word | bit } else { word & !bit };
+            addr.add(irq / 32).write_volatile(word);
         }
     }
 
     /// Claim an interupt that you were alerted to.
     pub fn claim(&self) -> u32 {
-        let addr = self.base as *mut u32;
-        let hart = read_tp() as usize;
-
-        const RAW_OFFSET: usize = 0x201004; // 4-bits after threshold
-        const RAW_STEP: usize = 0x2000;
-
-        let final_offset = (RAW_OFFSET + (hart * RAW_STEP))/4; //ASK / 4?
+        let hart = hart_id();
         unsafe {
             // returns highest-priority pending interrupt
-            addr.add(final_offset).read_volatile()
+            self.context_reg(hart).byte_add(CLAIM).read_volatile()
             // ^ reading mmapped register
         }
     }
 
     /// Alert the PLIC that we have completed the interupt we claimed
     pub fn complete(&self, irq: u32) {
-        let addr = self.base as *mut u32;
-        let hart = read_tp() as usize;
-
-        const RAW_OFFSET: usize = 0x201004;
-        const RAW_STEP: usize = 0x2000;
-
-        let final_offset = (RAW_OFFSET + (hart * RAW_STEP))/4; //ASK: / 4?
+        let hart = hart_id();
         unsafe {
             // signals completion of interrupt identified by IRQ
-            addr.add(final_offset).write_volatile(irq);
+            self.context_reg(hart).byte_add(CLAIM).write_volatile(irq);
             // ^ writing mmapped register
         }
     }
@@ -1,15 +1,16 @@
 //! Page table
-// VA: 39bits, PA: 56bits
+// VA: 39, 48 or 57 bits (see PagingMode), PA: 56bits
 // PTE size = 8 bytes
 // use crate::hw::riscv::*;
 
 use core::assert;
 use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::vec::Vec;
 
 use crate::vm::*;
 use crate::hal::*;                   // virt/hal stuff
 
-pub const VA_TOP: usize = 1 << (27 + 12); // 2^27 VPN + 12 Offset
 pub const PTE_TOP: usize = 512; // 4Kb / 8 byte PTEs = 512 PTEs / page!
 pub const PTE_VALID: usize = 1 << 0;
 pub const PTE_READ: usize = 1 << 1;
@@ -62,9 +63,133 @@ macro_rules! PteSetFlag {
     };
 }
 
+/// The paging schemes we know how to build tables for. They only
+/// differ in how many levels the tables have.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PagingMode {
+    Sv39,
+    Sv48,
+    Sv57,
+}
+
+impl PagingMode {
+    pub fn levels(self) -> usize {
+        match self {
+            PagingMode::Sv39 => 3,
+            PagingMode::Sv48 => 4,
+            PagingMode::Sv57 => 5,
+        }
+    }
+
+    /// The MODE field of satp
+    fn satp_mode(self) -> usize {
+        match self {
+            PagingMode::Sv39 => 8,
+            PagingMode::Sv48 => 9,
+            PagingMode::Sv57 => 10,
+        }
+    }
+}
+
+/// Levels in the tables we build. Set once on boot before any tables
+/// exist, see set_paging_mode
+static LEVELS: AtomicUsize = AtomicUsize::new(3);
+
+pub fn paging_mode() -> PagingMode {
+    match levels() {
+        3 => PagingMode::Sv39,
+        4 => PagingMode::Sv48,
+        _ => PagingMode::Sv57,
+    }
+}
+
+/// Only valid before any page table has been built
+pub fn set_paging_mode(mode: PagingMode) {
+    LEVELS.store(mode.levels(), Ordering::Release);
+}
+
+#[inline(always)]
+fn levels() -> usize {
+    LEVELS.load(Ordering::Relaxed)
+}
+
+/// One past the highest virtual address we map. Addresses in the
+/// upper (sign extended) half are not used.
+#[inline(always)]
+pub fn va_top() -> usize {
+    1 << (12 + 9 * levels())
+}
+
 #[inline(always)]
 fn phy_to_satp(ptr: PhysAddress) -> usize {
-    (1 << 63) | (ptr.addr() >> 12)
+    (paging_mode().satp_mode() << 60) | (ptr.addr() >> 12)
+}
+
+/// What to write to satp to use the table at base, with ASID 0
+pub fn satp_for(base: PhysAddress) -> usize {
+    phy_to_satp(base)
+}
+
+pub const SATP_ASID_SHIFT: usize = 44;
+pub const SATP_ASID_MASK: usize = 0xFFFF << SATP_ASID_SHIFT;
+
+/// Same as satp_for, but tagged with an ASID
+pub fn satp_with_asid(base: PhysAddress, asid: usize) -> usize {
+    phy_to_satp(base) | ((asid << SATP_ASID_SHIFT) & SATP_ASID_MASK)
+}
+
+/// Root table for probing. A leaf is allowed at the top level in all
+/// of the modes, but what an entry covers isn't the same. Under Sv48
+/// and Sv57 entry 0 covers at least the first 512G, so it identity
+/// maps the kernel. Under Sv39 an entry is only 1G, so there is a
+/// second leaf for the gigapage the kernel is in.
+#[repr(C, align(4096))]
+struct ProbeTable([usize; PTE_TOP]);
+
+static mut PROBE_TABLE: ProbeTable = ProbeTable([0; PTE_TOP]);
+
+const GIGAPAGE: usize = 1 << 30;
+
+/// Find the widest paging mode this hart supports, and how many ASID
+/// bits it has. satp ignores writes with a mode it doesn't know, and
+/// only keeps the ASID bits that exist, so we try each mode and see
+/// what sticks. Has to run with paging off, the kernel text in the
+/// bottom 512G and within a single 1G page.
+pub fn probe_paging() -> (PagingMode, usize) {
+    let text_start = HAL::text_start().addr();
+    assert!(HAL::text_end().addr() < 1 << 39);
+    assert!(text_start / GIGAPAGE == (HAL::text_end().addr() - 1) / GIGAPAGE,
+            "Kernel text crosses a 1G boundary, the Sv39 probe can't map it");
+    let leaf = PTE_VALID | PTE_READ | PTE_WRITE | PTE_EXEC | PTE_ACCESSED | PTE_DIRTY;
+    let giga = text_start / GIGAPAGE;
+    let probe = unsafe {
+        // if the kernel is in the first 1G these are the same entry
+        PROBE_TABLE.0[0] = leaf;
+        PROBE_TABLE.0[giga] = phy_to_pte((giga * GIGAPAGE) as PhysAddress) | leaf;
+        core::ptr::addr_of_mut!(PROBE_TABLE) as PhysAddress
+    };
+    for mode in [PagingMode::Sv57, PagingMode::Sv48, PagingMode::Sv39] {
+        let want = (mode.satp_mode() << 60) | SATP_ASID_MASK | (probe.addr() >> 12);
+        let got: usize;
+        unsafe {
+            // everything we run here is covered by the probe table,
+            // and we put satp back before touching anything else
+            asm!(
+                "sfence.vma zero, zero",
+                "csrw satp, {want}",
+                "csrr {got}, satp",
+                "csrw satp, zero",
+                "sfence.vma zero, zero",
+                want = in(reg) want,
+                got = out(reg) got,
+            );
+        }
+        if got >> 60 == mode.satp_mode() {
+            return (mode, (got & SATP_ASID_MASK).count_ones() as usize);
+        }
+    }
+    // everything with an MMU has Sv39, so we shouldn't get here
+    (PagingMode::Sv39, 0)
 }
 
 macro_rules! PageAlignDown {
@@ -126,16 +251,25 @@ impl PageTable {
     }
 }
 
-// Get the address of the PTE for va given the page table pt.
-// Returns Either PTE or None, callers responsibility to use PTE
-// or allocate a new page.
-unsafe fn walk(pt: PageTable, va: VirtAddress, alloc_new: bool) -> Result<*mut PTEntry, VmError> {
+/// Bytes mapped by one entry at a level. 4K at 0, 2M at 1, 1G at 2,
+/// and so on
+#[inline(always)]
+fn level_size(level: usize) -> usize {
+    PAGE_SIZE << (9 * level)
+}
+
+// Get the address of the PTE for va at the given level of the page
+// table pt, 0 being the 4K leaves. Missing tables on the way down are
+// allocated if alloc_new is set, and otherwise an error. Running into
+// a superpage leaf above the wanted level is an error either way.
+unsafe fn walk(pt: PageTable, va: VirtAddress, alloc_new: bool, level: usize) -> Result<*mut PTEntry, VmError> {
     let mut table = pt;
-    assert!(va.addr() < VA_TOP);
-    for level in (1..3).rev() {
-        let idx = vpn(va, level);
+    assert!(va.addr() < va_top());
+    for l in (level + 1..levels()).rev() {
+        let idx = vpn(va, l);
         let next: *mut PTEntry = table.index_mut(idx);
         table = match PteGetFlag!(*next, PTE_VALID) {
+            true if pte_is_leaf(*next) => return Err(VmError::PallocFail),
             true => PageTable::from(*next),
             false => {
                 if alloc_new {
@@ -152,14 +286,58 @@ unsafe fn walk(pt: PageTable, va: VirtAddress, alloc_new: bool) -> Result<*mut P
             }
         };
     }
-    // Last, return PTE leaf. Assuming we are all using 4K pages right now.
     // Caller's responsibility to check flags.
-    let idx = vpn(va, 0);
+    let idx = vpn(va, level);
     Ok(table.index_mut(idx))
 }
 
+/// Find the leaf PTE mapping va, whatever level it is at. Returns the
+/// PTE and its level, or None if va isn't mapped.
+fn walk_leaf(pt: PageTable, va: VirtAddress) -> Option<(*mut PTEntry, usize)> {
+    let mut table = pt;
+    if va.addr() >= va_top() {
+        return None;
+    }
+    for level in (0..levels()).rev() {
+        let pte_addr = table.index_mut(vpn(va, level));
+        let pte = read_pte(pte_addr);
+        if !PteGetFlag!(pte, PTE_VALID) {
+            return None;
+        }
+        if pte_is_leaf(pte) {
+            return Some((pte_addr, level));
+        }
+        table = PageTable::from(pte);
+    }
+    // a non-leaf at level 0 is malformed
+    None
+}
+
+/// Translate va in the page table pt. Returns the physical address
+/// (with va's offset in the page) and the PTE flag bits, or None if
+/// va isn't mapped.
+pub fn lookup(pt: PageTable, va: VirtAddress) -> Option<(PhysAddress, usize)> {
+    let (pte_addr, level) = walk_leaf(pt, va)?;
+    let pte = read_pte(pte_addr);
+    let offset = va.addr() & (level_size(level) - 1);
+    Some((pte_to_phy(pte).map_addr(|addr| addr + offset), pte & ((1 << 10) - 1)))
+}
+
+/// The biggest level we can put a leaf at for a mapping of va to pa,
+/// with remaining bytes left to map
+fn best_level(va: usize, pa: usize, remaining: usize) -> usize {
+    for level in (1..levels()).rev() {
+        let size = level_size(level);
+        if va.is_multiple_of(size) && pa.is_multiple_of(size) && remaining >= size {
+            return level;
+        }
+    }
+    0
+}
+
 /// Maps some number of pages into the VM given by pt of byte length
-/// size.
+/// size. Megapage and gigapage leaves are used where va, pa and size
+/// line up for them.
 pub fn page_map(
     pt: PageTable,
     va: VirtAddress,
@@ -169,11 +347,12 @@ pub fn page_map(
 ) -> Result<(), VmError> {
     // Round down to page aligned boundary (multiple of pg size).
     let mut start = PageAlignDown!(va);
-    let mut phys = pa;
-    let end = PageAlignDown!(va.map_addr(|addr| addr + (size - 1)));
+    let mut phys = PageAlignDown!(pa);
+    let end = PageAlignDown!(va.map_addr(|addr| addr + (size - 1))).addr() + PAGE_SIZE;
 
-    while start <= end {
-        let walk_addr = unsafe { walk(pt, start, true) };
+    while start.addr() < end {
+        let level = best_level(start.addr(), phys.addr(), end - start.addr());
+        let walk_addr = unsafe { walk(pt, start, true, level) };
         match walk_addr {
             Err(e) => {
                 return Err(e);
@@ -183,11 +362,309 @@ pub fn page_map(
                     return Err(VmError::PallocFail);
                 }
                 set_pte(pte_addr, PteSetFlag!(phy_to_pte(phys), flag | PTE_VALID));
-                start = start.map_addr(|addr| addr + PAGE_SIZE);
-                phys = phys.map_addr(|addr| addr + PAGE_SIZE);
+                start = start.map_addr(|addr| addr + level_size(level));
+                phys = phys.map_addr(|addr| addr + level_size(level));
             }
         }
     }
 
     Ok(())
 }
+
+/// Break the superpage leaf at pte_addr, which is at level, into a
+/// table of leaves one level down that map the same memory the same
+/// way
+fn split_leaf(pte_addr: *mut PTEntry, level: usize) -> Result<PageTable, VmError> {
+    assert!(level > 0);
+    let pte = read_pte(pte_addr);
+    let flags = pte & ((1 << 10) - 1);
+    let page = palloc()?;
+    let table = PageTable::new(page.addr);
+    let base = pte_to_phy(pte);
+    for idx in 0..PTE_TOP {
+        let phys = base.map_addr(|addr| addr + idx * level_size(level - 1));
+        set_pte(table.index_mut(idx), phy_to_pte(phys) | flags);
+    }
+    set_pte(pte_addr, PteSetFlag!(phy_to_pte(page.addr), PTE_VALID));
+    Ok(table)
+}
+
+#[inline(always)]
+fn pte_is_leaf(pte: PTEntry) -> bool {
+    pte & (PTE_READ | PTE_WRITE | PTE_EXEC) != 0
+}
+
+/// Copy every mapping in src into dest, which should be empty.
+/// Intermediate tables are always new. Leaf pages are shared between
+/// the two tables unless dup_leaves is set, in which case user pages
+/// get fresh copies (4K ones, even for superpages). Kernel leaves
+/// (text, stacks, mmio) are always shared, duplicating those makes no
+/// sense.
+///
+/// The copies are returned, and belong to the caller. dest only maps
+/// them, so free_tree leaves them alone like any other leaf.
+///
+/// On error dest holds a partial copy, which the caller should free.
+/// Any copies made before the error are freed here.
+pub fn deep_copy(src: PageTable, dest: PageTable, dup_leaves: bool) -> Result<Vec<Page>, VmError> {
+    let mut copies = Vec::new();
+    match copy_level(src, dest, levels() - 1, dup_leaves, &mut copies) {
+        Ok(()) => Ok(copies),
+        Err(e) => {
+            for page in copies {
+                // we just got these from palloc
+                let _ = pfree(page);
+            }
+            Err(e)
+        },
+    }
+}
+
+fn copy_level(src: PageTable, dest: PageTable, level: usize, dup_leaves: bool, copies: &mut Vec<Page>) -> Result<(), VmError> {
+    for idx in 0..PTE_TOP {
+        let pte = read_pte(src.index_mut(idx));
+        if !PteGetFlag!(pte, PTE_VALID) {
+            continue;
+        }
+        let flags = pte & ((1 << 10) - 1);
+        if pte_is_leaf(pte) {
+            set_pte(dest.index_mut(idx), pte);
+            if dup_leaves && PteGetFlag!(pte, PTE_USER) {
+                dup_leaf(dest.index_mut(idx), level, copies)?;
+            }
+        } else {
+            let page = palloc()?;
+            set_pte(dest.index_mut(idx), phy_to_pte(page.addr) | flags);
+            copy_level(PageTable::from(pte), PageTable::new(page.addr), level - 1, dup_leaves, copies)?;
+        }
+    }
+    Ok(())
+}
+
+/// Point the leaf at pte_addr at a fresh copy of what it maps, and
+/// add the copy to copies. We can't count on finding aligned physical
+/// memory for a superpage, so those get split into 4K copies.
+fn dup_leaf(pte_addr: *mut PTEntry, level: usize, copies: &mut Vec<Page>) -> Result<(), VmError> {
+    if level > 0 {
+        let table = split_leaf(pte_addr, level)?;
+        for idx in 0..PTE_TOP {
+            dup_leaf(table.index_mut(idx), level - 1, copies)?;
+        }
+        return Ok(());
+    }
+    let pte = read_pte(pte_addr);
+    let page = palloc()?;
+    unsafe {
+        page.addr.copy_from_nonoverlapping(pte_to_phy(pte), PAGE_SIZE / 8);
+    }
+    set_pte(pte_addr, phy_to_pte(page.addr) | (pte & ((1 << 10) - 1)));
+    copies.push(page);
+    Ok(())
+}
+
+fn table_is_empty(table: PageTable) -> bool {
+    (0..PTE_TOP).all(|idx| !PteGetFlag!(read_pte(table.index_mut(idx)), PTE_VALID))
+}
+
+/// Unmap size bytes starting at va. Intermediate tables left with no
+/// valid entries are unhooked from the tree and returned, so the
+/// caller can free them once no hart can still be walking them (after
+/// a TLB flush). Unmapping something that isn't mapped is fine. The
+/// root table is never returned.
+///
+/// Unmapping part of a superpage splits it first, which can fail to
+/// allocate. The tables unhooked before that are still returned.
+pub fn page_unmap(pt: PageTable, va: VirtAddress, size: usize) -> (Vec<Page>, Result<(), VmError>) {
+    let mut unhooked = Vec::new();
+    if size == 0 {
+        return (unhooked, Ok(()));
+    }
+    let start = PageAlignDown!(va).addr();
+    // partial pages at the end go too
+    let end = va.addr().saturating_add(size).min(va_top());
+    let end = (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let res = unmap_level(pt, levels() - 1, start, end, &mut unhooked).map(|_| ());
+    (unhooked, res)
+}
+
+/// Returns true if table has no valid entries left
+fn unmap_level(table: PageTable, level: usize, start: usize, end: usize, unhooked: &mut Vec<Page>) -> Result<bool, VmError> {
+    let span = level_size(level);
+    let mut addr = start;
+    while addr < end {
+        let next = (addr & !(span - 1)) + span;
+        let pte_addr = table.index_mut(vpn(addr as VirtAddress, level));
+        let mut pte = read_pte(pte_addr);
+        if PteGetFlag!(pte, PTE_VALID) {
+            let whole = addr.is_multiple_of(span) && end >= next;
+            if pte_is_leaf(pte) && whole {
+                set_pte(pte_addr, 0);
+                addr = next;
+                continue;
+            }
+            if pte_is_leaf(pte) {
+                // only part of a superpage, break it up and go on
+                // with the pieces
+                split_leaf(pte_addr, level)?;
+                pte = read_pte(pte_addr);
+            }
+            let child = PageTable::from(pte);
+            if unmap_level(child, level - 1, addr, end.min(next), unhooked)? {
+                set_pte(pte_addr, 0);
+                unhooked.push(Page::from(child.base));
+            }
+        }
+        addr = next;
+    }
+    Ok(table_is_empty(table))
+}
+
+/// Free every table page in the tree rooted at pt, pt included. The
+/// pages the tree maps are not touched, they belong to whoever mapped
+/// them.
+pub fn free_tree(pt: PageTable) -> Result<(), VmError> {
+    free_level(pt, levels() - 1)
+}
+
+fn free_level(table: PageTable, level: usize) -> Result<(), VmError> {
+    if level > 0 {
+        for idx in 0..PTE_TOP {
+            let pte = read_pte(table.index_mut(idx));
+            if PteGetFlag!(pte, PTE_VALID) && !pte_is_leaf(pte) {
+                free_level(PageTable::from(pte), level - 1)?;
+            }
+        }
+    }
+    pfree(Page::from(table.base))
+}
+
+/// Whether every page in size bytes from va is mapped
+pub fn range_is_mapped(pt: PageTable, va: VirtAddress, size: usize) -> bool {
+    let mut addr = PageAlignDown!(va).addr();
+    let end = va.addr().saturating_add(size);
+    while addr < end {
+        match walk_leaf(pt, addr as VirtAddress) {
+            None => return false,
+            Some((_, level)) => {
+                addr = (addr & !(level_size(level) - 1)) + level_size(level);
+            },
+        }
+    }
+    true
+}
+
+/// Replace the flags of every leaf in size bytes from va, keeping
+/// where they point. Superpages only partly in the range are split
+/// first, which is the only way this can fail. Holes in the range are
+/// skipped, see range_is_mapped.
+pub fn page_protect(pt: PageTable, va: VirtAddress, size: usize, flag: usize) -> Result<(), VmError> {
+    if size == 0 {
+        return Ok(());
+    }
+    let start = PageAlignDown!(va).addr();
+    let end = va.addr().saturating_add(size).min(va_top());
+    let end = (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    protect_level(pt, levels() - 1, start, end, flag)
+}
+
+fn protect_level(table: PageTable, level: usize, start: usize, end: usize, flag: usize) -> Result<(), VmError> {
+    let span = level_size(level);
+    let mut addr = start;
+    while addr < end {
+        let next = (addr & !(span - 1)) + span;
+        let pte_addr = table.index_mut(vpn(addr as VirtAddress, level));
+        let mut pte = read_pte(pte_addr);
+        if PteGetFlag!(pte, PTE_VALID) {
+            let whole = addr.is_multiple_of(span) && end >= next;
+            if pte_is_leaf(pte) && whole {
+                set_pte(pte_addr, PteSetFlag!(phy_to_pte(pte_to_phy(pte)), flag | PTE_VALID));
+                addr = next;
+                continue;
+            }
+            if pte_is_leaf(pte) {
+                split_leaf(pte_addr, level)?;
+                pte = read_pte(pte_addr);
+            }
+            protect_level(PageTable::from(pte), level - 1, addr, end.min(next), flag)?;
+        }
+        addr = next;
+    }
+    Ok(())
+}
+
+/// One leaf of a page table, as found by MappingIter
+pub struct Mapping {
+    pub va: VirtAddress,
+    pub pa: PhysAddress,
+    /// Bytes covered, more than a page for superpages
+    pub size: usize,
+    /// The PTE flag bits
+    pub flags: usize,
+}
+
+/// Walks every leaf in a page table, in address order. The table
+/// shouldn't change while this is in use.
+pub struct MappingIter {
+    tables: [PageTable; 5],
+    idx: [usize; 5],
+    level: usize,
+    done: bool,
+}
+
+impl MappingIter {
+    pub fn new(pt: PageTable) -> Self {
+        let top = levels() - 1;
+        Self {
+            tables: [pt; 5],
+            idx: [0; 5],
+            level: top,
+            done: false,
+        }
+    }
+
+    fn va(&self) -> usize {
+        (self.level..levels())
+            .map(|l| self.idx[l] << (12 + 9 * l))
+            .sum()
+    }
+}
+
+impl Iterator for MappingIter {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        while !self.done {
+            let level = self.level;
+            if self.idx[level] == PTE_TOP {
+                // done with this table, back up to its parent
+                if level == levels() - 1 {
+                    self.done = true;
+                } else {
+                    self.level += 1;
+                    self.idx[self.level] += 1;
+                }
+                continue;
+            }
+            let pte = read_pte(self.tables[level].index_mut(self.idx[level]));
+            if !PteGetFlag!(pte, PTE_VALID) || (level == 0 && !pte_is_leaf(pte)) {
+                self.idx[level] += 1;
+                continue;
+            }
+            if pte_is_leaf(pte) {
+                let out = Mapping {
+                    va: self.va() as VirtAddress,
+                    pa: pte_to_phy(pte),
+                    size: level_size(level),
+                    flags: pte & ((1 << 10) - 1),
+                };
+                self.idx[level] += 1;
+                return Some(out);
+            }
+            // down into the next table
+            self.level -= 1;
+            self.tables[self.level] = PageTable::from(pte);
+            self.idx[self.level] = 0;
+        }
+        None
+    }
+}
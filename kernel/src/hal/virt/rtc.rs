@@ -0,0 +1,22 @@
+//! Goldfish RTC, which qemu virt has for wall clock time. It counts
+//! nanoseconds since the unix epoch, and we only ever read it.
+// Register layout from the goldfish virtual hardware docs in the
+// android emulator sources (GOLDFISH-VIRTUAL-HARDWARE.TXT)
+
+use super::*;
+
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+
+/// The time since the epoch, if discovery found an RTC
+pub fn read() -> Option<Duration> {
+    let base = HAL::mmio_devices(DeviceKind::Rtc).first()?.base;
+    let nanos = unsafe {
+        // reading the low half latches the high half, so this order
+        // gives a consistent value
+        let low = ((base + TIME_LOW) as *const u32).read_volatile();
+        let high = ((base + TIME_HIGH) as *const u32).read_volatile();
+        (high as u64) << 32 | low as u64
+    };
+    Some(Duration::from_nanos(nanos))
+}
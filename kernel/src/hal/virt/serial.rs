@@ -0,0 +1,288 @@
+//! Buffering between the kernel and whichever console device is in
+//! use (Console in virt.rs). Input is kept in a ring until someone
+//! reads it. Output collects in another ring until a newline, a full
+//! ring, or a flush.
+//!
+//! Lock order is BUFFERS, then whatever the device takes. Nothing in
+//! here may log or panic on a device error, as logging (and the panic
+//! handler, through panic_put_bytes) comes back through here.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::*;
+use crate::lock::irqmutex::IrqMutex;
+
+/// What a console device has to provide for the buffering to sit on
+pub trait RawSerial {
+    /// Whether input raises an interrupt that ends up calling
+    /// serial::poll. Without one, input only shows up when polled.
+    const HAS_IRQ: bool;
+
+    /// Where the device is, if the kernel page table needs to map it
+    const MMIO_BASE: Option<usize> = None;
+
+    fn setup() -> Result<(), HALSerialError>;
+
+    /// Hook up the input interrupt, if there is one. Called once the
+    /// PLIC is up, which is after setup.
+    fn irq_setup() {}
+
+    /// Break any lock put_bytes takes, see serial::panic_put_bytes
+    ///
+    /// # Safety
+    /// Only from the panic handler.
+    unsafe fn force_unlock() {}
+
+    /// Write all of bytes, waiting on the device as needed
+    fn put_bytes(bytes: &[u8]) -> Result<(), HALSerialError>;
+
+    /// A byte of input, if the device has one waiting
+    fn get_byte() -> Result<Option<u8>, HALSerialError>;
+}
+
+const RX_BUFFER_SIZE: usize = 256;
+const TX_BUFFER_SIZE: usize = 1024;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+/// Fixed size byte queue. Pushing onto a full one drops the byte.
+struct ByteRing<const N: usize> {
+    buf: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> ByteRing<N> {
+    const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    fn push(&mut self, byte: u8) {
+        if !self.is_full() {
+            self.buf[(self.head + self.len) % N] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+
+    /// The contents in order, as the two pieces either side of the
+    /// wrap
+    fn as_slices(&self) -> (&[u8], &[u8]) {
+        let end = self.head + self.len;
+        if end <= N {
+            (&self.buf[self.head..end], &[])
+        } else {
+            (&self.buf[self.head..], &self.buf[..end - N])
+        }
+    }
+
+    fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+}
+
+struct Buffers {
+    rx: ByteRing<RX_BUFFER_SIZE>,
+    tx: ByteRing<TX_BUFFER_SIZE>,
+}
+
+static BUFFERS: IrqMutex<Buffers> = IrqMutex::new(Buffers {
+    rx: ByteRing::new(),
+    tx: ByteRing::new(),
+});
+
+/// Called whenever input arrives, see on_input
+static ON_INPUT: IrqMutex<Option<fn()>> = IrqMutex::new(None);
+
+/// Set once Console::setup has worked. Until then (or forever, if it
+/// didn't) everything fails with Unavailable rather than touching the
+/// device.
+static READY: AtomicBool = AtomicBool::new(false);
+
+fn check_ready() -> Result<(), HALSerialError> {
+    if READY.load(Ordering::Acquire) {
+        Ok(())
+    } else {
+        Err(HALSerialError::Unavailable)
+    }
+}
+
+pub fn setup() -> Result<(), HALSerialError> {
+    Console::setup()?;
+    READY.store(true, Ordering::Release);
+    Ok(())
+}
+
+pub fn irq_setup() {
+    Console::irq_setup();
+}
+
+/// Write out the tx ring. It is emptied even on failure, there's no
+/// point holding on to output we couldn't send.
+fn flush_locked(buffers: &mut Buffers) -> Result<(), HALSerialError> {
+    let (first, second) = buffers.tx.as_slices();
+    let out = Console::put_bytes(first).and_then(|_| Console::put_bytes(second));
+    buffers.tx.clear();
+    out
+}
+
+pub fn put_bytes(bytes: &[u8]) -> Result<(), HALSerialError> {
+    check_ready()?;
+    let mut buffers = BUFFERS.lock();
+    for &byte in bytes {
+        if buffers.tx.is_full() {
+            flush_locked(&mut buffers)?;
+        }
+        buffers.tx.push(byte);
+        // line buffered, so logs show up as they happen
+        if byte == b'\n' {
+            flush_locked(&mut buffers)?;
+        }
+    }
+    Ok(())
+}
+
+/// put_bytes for the panic handler. Whoever had the console when we
+/// panicked (this hart included) isn't coming back for it, so its
+/// locks are broken rather than waited on.
+pub fn panic_put_bytes(bytes: &[u8]) -> Result<(), HALSerialError> {
+    check_ready()?;
+    unsafe {
+        BUFFERS.force_unlock();
+        Console::force_unlock();
+    }
+    put_bytes(bytes)
+}
+
+pub fn flush() -> Result<(), HALSerialError> {
+    check_ready()?;
+    flush_locked(&mut BUFFERS.lock())
+}
+
+/// Move any input the device has into the ring, and tell whoever
+/// asked if there was some. The UART interrupt lands here too.
+pub fn poll() -> Result<(), HALSerialError> {
+    check_ready()?;
+    let (arrived, out) = {
+        let mut buffers = BUFFERS.lock();
+        let mut arrived = false;
+        let out = loop {
+            match Console::get_byte() {
+                Ok(Some(byte)) => {
+                    buffers.rx.push(byte);
+                    arrived = true;
+                },
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        (arrived, out)
+    };
+    // anything that did arrive before a failure is still input
+    if arrived {
+        // copied out so the callback can replace itself
+        let notify = *ON_INPUT.lock();
+        if let Some(notify) = notify {
+            notify();
+        }
+    }
+    out
+}
+
+pub fn on_input(notify: fn()) {
+    *ON_INPUT.lock() = Some(notify);
+}
+
+pub fn input_ready() -> Result<bool, HALSerialError> {
+    poll()?;
+    Ok(!BUFFERS.lock().rx.is_empty())
+}
+
+pub fn try_read() -> Result<Option<u8>, HALSerialError> {
+    poll()?;
+    Ok(BUFFERS.lock().rx.pop())
+}
+
+pub fn read_nonblocking(buf: &mut [u8]) -> Result<usize, HALSerialError> {
+    poll()?;
+    let mut buffers = BUFFERS.lock();
+    let mut count = 0;
+    while count < buf.len() {
+        match buffers.rx.pop() {
+            Some(byte) => {
+                buf[count] = byte;
+                count += 1;
+            },
+            None => break,
+        }
+    }
+    Ok(count)
+}
+
+/// Wait for a byte of input. If the console has an interrupt we sleep
+/// for it, otherwise we have no choice but to spin. Must not be called
+/// holding an IrqMutex, as it may turn interrupts on.
+pub fn read_byte() -> Result<u8, HALSerialError> {
+    loop {
+        if let Some(byte) = try_read()? {
+            return Ok(byte);
+        }
+        if Console::HAS_IRQ {
+            HAL::wait_for_interrupt();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Read a line with echo, handling backspace. The line ending isn't
+/// stored. Input past the end of buf is dropped until the line ends.
+pub fn read_line(buf: &mut [u8]) -> Result<usize, HALSerialError> {
+    let mut len = 0;
+    loop {
+        match read_byte()? {
+            b'\r' | b'\n' => {
+                put_bytes(b"\r\n")?;
+                return Ok(len);
+            },
+            BACKSPACE | DELETE => {
+                if len > 0 {
+                    len -= 1;
+                    put_bytes(&[BACKSPACE, b' ', BACKSPACE])?;
+                    flush()?;
+                }
+            },
+            byte => {
+                if len < buf.len() {
+                    buf[len] = byte;
+                    len += 1;
+                    put_bytes(&[byte])?;
+                    flush()?;
+                }
+            },
+        }
+    }
+}
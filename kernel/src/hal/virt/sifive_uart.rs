@@ -0,0 +1,118 @@
+//! Driver for the SiFive UART on the sifive_u machine, which is the
+//! console there. Like uart.rs, buffering is left to serial.rs and
+//! input raises an interrupt that hands off to serial::poll.
+// Register layout from the UART chapter of the SiFive FU540-C000 manual
+
+use super::*;
+use crate::lock::irqmutex::IrqMutex;
+
+const TXDATA: usize = 0x00; // Transmit data, top bit set while the fifo is full
+const RXDATA: usize = 0x04; // Receive data, top bit set while the fifo is empty
+const TXCTRL: usize = 0x08; // Transmit control
+const RXCTRL: usize = 0x0c; // Receive control
+const IE: usize = 0x10; // Interrupt enable
+
+const TXDATA_FULL: u32 = 1 << 31;
+const RXDATA_EMPTY: u32 = 1 << 31;
+const TXCTRL_TXEN: u32 = 1 << 0;
+const RXCTRL_RXEN: u32 = 1 << 0;
+/// Interrupt while the rx fifo holds more than the watermark, which
+/// we leave at 0
+const IE_RXWM: u32 = 1 << 1;
+
+pub struct SifiveUart {
+    base_address: usize,
+}
+
+impl SifiveUart {
+    pub const fn new(base: usize) -> Self {
+        Self {
+            base_address: base
+        }
+    }
+
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base_address + offset) as *mut u32
+    }
+
+    /// Turn tx and rx on, with interrupts off. The baud divisor is
+    /// left as opensbi set it.
+    pub fn init(&mut self) {
+        unsafe {
+            self.reg(IE).write_volatile(0);
+            self.reg(TXCTRL).write_volatile(TXCTRL_TXEN);
+            self.reg(RXCTRL).write_volatile(RXCTRL_RXEN);
+        }
+    }
+
+    pub fn enable_rx_interrupt(&mut self) {
+        unsafe {
+            self.reg(IE).write_volatile(IE_RXWM);
+        }
+    }
+
+    pub fn put(&mut self, c: u8) {
+        unsafe {
+            while self.reg(TXDATA).read_volatile() & TXDATA_FULL != 0 {
+                core::hint::spin_loop();
+            }
+            self.reg(TXDATA).write_volatile(c as u32);
+        }
+    }
+
+    pub fn get(&mut self) -> Option<u8> {
+        // the read pops the fifo, so check and take in one go
+        let data = unsafe { self.reg(RXDATA).read_volatile() };
+        if data & RXDATA_EMPTY != 0 {
+            None
+        } else {
+            Some(data as u8)
+        }
+    }
+}
+
+static DEV: IrqMutex<SifiveUart> = IrqMutex::new(SifiveUart::new(board::UART.base));
+
+fn handle_irq(_irq: usize) {
+    // the uart itself can't fail
+    let _ = serial::poll();
+}
+
+pub struct SifiveUartConsole;
+
+impl serial::RawSerial for SifiveUartConsole {
+    const HAS_IRQ: bool = true;
+    const MMIO_BASE: Option<usize> = Some(board::UART.base);
+
+    fn setup() -> Result<(), HALSerialError> {
+        DEV.lock().init();
+        Ok(())
+    }
+
+    fn irq_setup() {
+        // there are two of these, find ours
+        let irq = HAL::mmio_devices(DeviceKind::Uart).iter()
+            .find(|dev| dev.base == board::UART.base)
+            .and_then(|dev| dev.irq)
+            .or(board::UART.irq)
+            .expect("No interrupt for the UART") as usize;
+        HAL::register_irq(irq, 1, handle_irq).expect("Could not register the UART interrupt");
+        DEV.lock().enable_rx_interrupt();
+    }
+
+    fn put_bytes(bytes: &[u8]) -> Result<(), HALSerialError> {
+        let mut dev = DEV.lock();
+        for byte in bytes {
+            dev.put(*byte);
+        }
+        Ok(())
+    }
+
+    fn get_byte() -> Result<Option<u8>, HALSerialError> {
+        Ok(DEV.lock().get())
+    }
+
+    unsafe fn force_unlock() {
+        DEV.force_unlock();
+    }
+}
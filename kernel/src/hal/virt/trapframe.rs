@@ -0,0 +1,64 @@
+//! The saved state of whatever a trap interrupted. regular_strap (see
+//! asm/trap.s) builds one of these on the interrupt stack and hands
+//! s_handler a pointer to it. Anything changed here is what the trap
+//! returns to.
+
+use crate::hal::HALTrapFrame;
+
+const SSTATUS_SPP: usize = 1 << 8;
+
+/// Layout must match save_trap_frame and load_trap_frame in
+/// asm/macro.s
+#[repr(C)]
+#[derive(Debug)]
+pub struct TrapFrame {
+    /// x0 through x31. x0 is always 0, and x2 is the interrupted sp,
+    /// not the interrupt stack
+    pub regs: [usize; 32],
+    pub sepc: usize,
+    pub sstatus: usize,
+    pub scause: usize,
+    pub stval: usize,
+}
+
+// so the asm and this can't drift apart quietly
+const _: () = assert!(core::mem::size_of::<TrapFrame>() == 288);
+
+/// Register numbers, for indexing regs
+pub const REG_RA: usize = 1;
+pub const REG_SP: usize = 2;
+pub const REG_GP: usize = 3;
+pub const REG_TP: usize = 4;
+pub const REG_S0: usize = 8;
+pub const REG_A0: usize = 10;
+pub const REG_A7: usize = 17;
+
+impl TrapFrame {
+    /// Whether this trap is an interrupt, rather than an exception
+    pub fn is_interrupt(&self) -> bool {
+        self.scause >> (usize::BITS - 1) != 0
+    }
+}
+
+impl HALTrapFrame for TrapFrame {
+    fn pc(&self) -> usize {
+        self.sepc
+    }
+
+    fn set_pc(&mut self, pc: usize) {
+        self.sepc = pc;
+    }
+
+    fn sp(&self) -> usize {
+        self.regs[REG_SP]
+    }
+
+    fn is_user(&self) -> bool {
+        // SPP is the mode we trapped from, 0 for U
+        self.sstatus & SSTATUS_SPP == 0
+    }
+
+    fn fault_addr(&self) -> usize {
+        self.stval
+    }
+}
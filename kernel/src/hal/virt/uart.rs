@@ -0,0 +1,140 @@
+//! Driver code for the NS16550A UART MM I/O device, used as the
+//! primary serial port instead of the opensbi debug console when the
+//! uart-ns16550a feature is on.
+//!
+//! Buffering is serial.rs's job, this is just the device. Input
+//! raises the UART interrupt, which hands off to serial::poll.
+// Referenced from:
+// https://github.com/mit-pdos/xv6-riscv/blob/riscv/kernel/uart.c
+// from https://github.com/sgmarz/osblog/tree/master/risc_v/src
+
+use super::*;
+use crate::lock::irqmutex::IrqMutex;
+
+/// Where qemu virt puts the UART. Serial comes up before discovery,
+/// so we can't ask.
+pub const UART_BASE: usize = 0x10000000;
+
+const RHR: usize = 0; // Receive Holding Register (read)
+const THR: usize = 0; // Transmit Holding Register (write)
+const IER: usize = 1; // Interrupt Enable Register
+const FCR: usize = 2; // FIFO Control Register (see uart layout in reference)
+const LCR: usize = 3; // Line Control Register (baud rate stuff)
+const LSR: usize = 5; // Line Status Register (ready to rx, ready to tx signals)
+
+const IER_RX_ENABLE: u8 = 1 << 0;
+const LSR_RX_READY: u8 = 1 << 0;
+const LSR_TX_IDLE: u8 = 1 << 5;
+
+pub struct Uart {
+    base_address: usize,
+}
+
+impl Uart {
+    pub const fn new(base: usize) -> Self {
+        Self {
+            base_address: base
+        }
+    }
+
+    /// Set the line up, with all device interrupts off
+    pub fn init(&mut self) {
+        // https://mth.st/blog/riscv-qemu/AN-491.pdf <-- inclues 16650A ref
+        let ptr = self.base_address as *mut u8;
+        // Basic semantics:
+        // `ptr` is a memory address.
+        // We want to write certain values to 'registers' located
+        // at specific offsets, calculated by ptr + register_offset.
+        // Then, we perform volatile writes to that location in memory
+        // to configure the specific parameters of the Qemu virt machine
+        // uart device without altering our base address.
+        unsafe {
+            // Disable interrupts first.
+            ptr.add(IER).write_volatile(0x0);
+            // Mode in order to set baud rate.
+            ptr.add(LCR).write_volatile(1 << 7);
+            // baud rate of 38.4k
+            ptr.add(0).write_volatile(0x03); // LSB (tx side)
+            ptr.add(1).write_volatile(0x00); // MST (rx side)
+            // 8 bit words (no parity)
+            ptr.add(LCR).write_volatile(3);
+            // Enable and clear FIFO
+            ptr.add(FCR).write_volatile(1 << 0 | 3 << 1);
+        }
+    }
+
+    /// Interrupt when input arrives. We never want tx interrupts, put
+    /// just waits for the holding register instead.
+    pub fn enable_rx_interrupt(&mut self) {
+        let ptr = self.base_address as *mut u8;
+        unsafe {
+            ptr.add(IER).write_volatile(IER_RX_ENABLE);
+        }
+    }
+
+    pub fn put(&mut self, c: u8) {
+        let ptr = self.base_address as *mut u8;
+        unsafe {
+            while ptr.add(LSR).read_volatile() & LSR_TX_IDLE == 0 {
+                core::hint::spin_loop();
+            }
+            ptr.add(THR).write_volatile(c);
+        }
+    }
+
+    pub fn get(&mut self) -> Option<u8> {
+        let ptr = self.base_address as *mut u8;
+        unsafe {
+            if ptr.add(LSR).read_volatile() & LSR_RX_READY == 0 {
+                // The DR bit is 0, meaning no data
+                None
+            } else {
+                // The DR bit is 1, meaning data!
+                Some(ptr.add(RHR).read_volatile())
+            }
+        }
+    }
+}
+
+static DEV: IrqMutex<Uart> = IrqMutex::new(Uart::new(UART_BASE));
+
+fn handle_irq(_irq: usize) {
+    // the uart itself can't fail
+    let _ = serial::poll();
+}
+
+pub struct UartConsole;
+
+impl serial::RawSerial for UartConsole {
+    const HAS_IRQ: bool = true;
+    const MMIO_BASE: Option<usize> = Some(UART_BASE);
+
+    fn setup() -> Result<(), HALSerialError> {
+        DEV.lock().init();
+        Ok(())
+    }
+
+    fn irq_setup() {
+        let irq = HAL::mmio_devices(DeviceKind::Uart).first()
+            .and_then(|dev| dev.irq)
+            .map_or(plic::UART_IRQ, |irq| irq as usize);
+        HAL::register_irq(irq, 1, handle_irq).expect("Could not register the UART interrupt");
+        DEV.lock().enable_rx_interrupt();
+    }
+
+    fn put_bytes(bytes: &[u8]) -> Result<(), HALSerialError> {
+        let mut dev = DEV.lock();
+        for byte in bytes {
+            dev.put(*byte);
+        }
+        Ok(())
+    }
+
+    fn get_byte() -> Result<Option<u8>, HALSerialError> {
+        Ok(DEV.lock().get())
+    }
+
+    unsafe fn force_unlock() {
+        DEV.force_unlock();
+    }
+}
@@ -0,0 +1,109 @@
+//! Per hart data. Every hart gets its own HartLocal at boot, set up
+//! by the HAL before any rust runs there, and finds it again with
+//! this_hart().
+//!
+//! A block is only ever touched by the hart it belongs to, so plain
+//! Cells are enough. Anything also used from trap handlers should be
+//! changed with interrupts off, like the irqmutex counters are.
+
+use core::cell::{Cell, RefCell};
+
+use crate::hal::*;
+use crate::process::{Process, ProcessQueue};
+
+/// Words of scratch for the HAL's trap path. They come first in the
+/// block, so asm can find them at the start of it.
+pub const TRAP_SCRATCH_WORDS: usize = 4;
+
+#[repr(C)]
+pub struct HartLocal {
+    /// The HAL's to use, see TRAP_SCRATCH_WORDS
+    pub trap_scratch: [Cell<usize>; TRAP_SCRATCH_WORDS],
+    hart_id: Cell<usize>,
+    /// Set while this hart is off running a process, see
+    /// HALSwitch::save_process
+    current_process: Cell<Option<Process>>,
+    /// How many push_off calls are outstanding, see irqmutex.rs
+    pub noff: Cell<usize>,
+    /// Whether interrupts were on before the first push_off
+    pub intena: Cell<bool>,
+    /// How many traps deep this hart is
+    pub trap_depth: Cell<usize>,
+    /// Processes meant for this hart only, checked before the shared
+    /// queue (see scheduler_loop). Nothing is pinned to a hart yet.
+    pub run_queue: RefCell<ProcessQueue>,
+}
+
+// Each hart only ever gets at its own block, through this_hart
+unsafe impl Sync for HartLocal {}
+
+impl HartLocal {
+    pub const fn new() -> Self {
+        // Cell<usize> isn't Copy, so no [Cell::new(0); N]
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: Cell<usize> = Cell::new(0);
+        Self {
+            trap_scratch: [ZERO; TRAP_SCRATCH_WORDS],
+            hart_id: Cell::new(0),
+            current_process: Cell::new(None),
+            noff: Cell::new(0),
+            intena: Cell::new(false),
+            trap_depth: Cell::new(0),
+            run_queue: RefCell::new(ProcessQueue::new()),
+        }
+    }
+
+    /// For the HAL, once when the hart comes up
+    pub fn init(&self, hart_id: usize) {
+        self.hart_id.set(hart_id);
+    }
+
+    pub fn hart_id(&self) -> usize {
+        self.hart_id.get()
+    }
+
+    /// Hold onto the process this hart is about to run
+    pub fn set_current_process(&self, proc: Process) {
+        let prev = self.current_process.replace(Some(proc));
+        assert!(prev.is_none(), "Hart {} already has a current process!", self.hart_id());
+    }
+
+    /// Take back the process this hart was running, if any
+    pub fn take_current_process(&self) -> Option<Process> {
+        self.current_process.take()
+    }
+
+    /// The id of the process this hart is running, if any, leaving it
+    /// where it is
+    pub fn current_process_id(&self) -> Option<usize> {
+        let proc = self.current_process.take();
+        let id = proc.as_ref().map(Process::id);
+        self.current_process.set(proc);
+        id
+    }
+
+    /// Note that we took a trap, giving how many deep we now are.
+    /// Pair with trap_exit.
+    pub fn trap_enter(&self) -> usize {
+        let depth = self.trap_depth.get() + 1;
+        self.trap_depth.set(depth);
+        depth
+    }
+
+    pub fn trap_exit(&self) {
+        let depth = self.trap_depth.get();
+        assert!(depth != 0, "trap_exit without trap_enter!");
+        self.trap_depth.set(depth - 1);
+    }
+}
+
+impl Default for HartLocal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// This hart's block
+pub fn this_hart() -> &'static HartLocal {
+    HAL::hart_local()
+}
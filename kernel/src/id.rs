@@ -5,26 +5,60 @@ use alloc::collections::BTreeSet;
 
 pub struct IdGenerator {
     counter: usize,
+    start: usize,
+    end: usize,
     in_use: BTreeSet<usize>,
 }
 
 impl IdGenerator {
     pub fn new() -> Self {
+        Self::with_range(0, usize::MAX)
+    }
+
+    /// Only hand out ids in start..end
+    pub const fn with_range(start: usize, end: usize) -> Self {
+        assert!(start < end, "Empty id range!");
         Self {
-            counter: 0,
+            counter: start,
+            start,
+            end,
             in_use: BTreeSet::new()
         }
     }
 
+    fn next(&self, id: usize) -> usize {
+        if id + 1 >= self.end {
+            self.start
+        } else {
+            id + 1
+        }
+    }
+
     pub fn generate(&mut self) -> usize {
+        match self.try_generate() {
+            Some(id) => id,
+            None => panic!("Ran out of ids!"),
+        }
+    }
+
+    /// Like generate, but None when every id is in use
+    pub fn try_generate(&mut self) -> Option<usize> {
+        if self.in_use.len() >= self.end - self.start {
+            return None;
+        }
         while self.in_use.contains(&self.counter) {
-            self.counter = self.counter.wrapping_add(1);
+            self.counter = self.next(self.counter);
         }
 
         self.in_use.insert(self.counter);
         let out = self.counter;
-        self.counter = self.counter.wrapping_add(1); // slightly faster
-        out
+        self.counter = self.next(self.counter); // slightly faster
+        Some(out)
+    }
+
+    /// Free every id at once
+    pub fn clear(&mut self) {
+        self.in_use.clear();
     }
 
     pub fn free(&mut self, id: usize) {
@@ -1,4 +1,5 @@
 //! Kernel locks.
 pub mod mutex;
+pub mod irqmutex;
 pub mod rw;
 pub mod condition;
@@ -0,0 +1,104 @@
+//! Spinlock mutex that also keeps interrupts off while held
+///
+/// A plain Mutex is fine for anything that is never touched from a
+/// trap handler. Anything that is needs one of these, otherwise a
+/// handler that interrupts the holder will spin forever on the lock.
+///
+/// Interrupt disabling nests per hart, like xv6's push_off/pop_off:
+/// interrupts only come back on once the last outstanding guard is
+/// dropped, and only if they were on before the first.
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::sync::atomic::*;
+
+use crate::hal::*;
+use crate::hartlocal::this_hart;
+
+/// Turn interrupts off on this hart, remembering whether they were on
+/// if this is the outermost call. Pair with pop_off.
+pub fn push_off() {
+    let was_on = HAL::interrupts_off();
+    let hart = this_hart();
+    let noff = hart.noff.get();
+    if noff == 0 {
+        hart.intena.set(was_on);
+    }
+    hart.noff.set(noff + 1);
+}
+
+/// Undo one push_off. Interrupts come back on after the last one, if
+/// they were on to begin with.
+pub fn pop_off() {
+    assert!(!HAL::interrupts_enabled(), "pop_off with interrupts on!");
+    let hart = this_hart();
+    let prev = hart.noff.get();
+    assert!(prev != 0, "pop_off without push_off!");
+    hart.noff.set(prev - 1);
+    if prev == 1 && hart.intena.get() {
+        HAL::interrupts_on();
+    }
+}
+
+/// Returned from successfully locking an IrqMutex.
+pub struct IrqMutexGuard<'a, T> {
+    mutex: &'a IrqMutex<T>,
+}
+
+impl<T> core::ops::Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.inner.get() }
+    }
+}
+
+impl<T> core::ops::DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.inner.get() }
+    }
+}
+
+impl<T> core::ops::Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // unlock before interrupts can come back
+        self.mutex.lock_state.store(0, Ordering::Release);
+        pop_off();
+    }
+}
+
+/// Same as Mutex, but with interrupts off on this hart while it is
+/// held (or being waited for).
+pub struct IrqMutex<T> {
+    lock_state: AtomicU32, // (0,1) = (unlocked, locked)
+    inner: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for IrqMutex<T> {}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        IrqMutex {
+            lock_state: AtomicU32::new(0),
+            inner: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        // interrupts go off first, so a handler can't come in between
+        // taking the lock and turning them off
+        push_off();
+        while self.lock_state.swap(1, Ordering::Acquire) == 1 {
+            spin_loop();
+        }
+        IrqMutexGuard { mutex: self }
+    }
+
+    /// Unlock without a guard, whoever holds it. Only for the panic
+    /// handler, where the holder (maybe this hart) is never coming
+    /// back to let go.
+    ///
+    /// # Safety
+    /// The holder must never touch the contents again.
+    pub unsafe fn force_unlock(&self) {
+        self.lock_state.store(0, Ordering::Release);
+    }
+}
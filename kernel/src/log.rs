@@ -3,11 +3,13 @@
 use core::fmt::{Write, Error};
 
 use crate::hal::*;
-use crate::lock::mutex::Mutex;
+use crate::lock::irqmutex::IrqMutex;
 // use crate::sbi_nputs;
 
 /// Wrapper for the HAL provided serial console. Ensure atomicity and nice rust bindings
-pub static PRIMARY_SERIAL_PASS: Mutex<SerialPass> = Mutex::new(SerialPass {_ignore: ()});
+///
+/// Trap handlers log too, so this has to keep interrupts off
+pub static PRIMARY_SERIAL_PASS: IrqMutex<SerialPass> = IrqMutex::new(SerialPass {_ignore: ()});
 
 pub struct SerialPass {
     _ignore: (),
@@ -15,8 +17,21 @@ pub struct SerialPass {
 
 impl Write for SerialPass {
     fn write_str(&mut self, out: &str) -> Result<(), Error> {
-        HAL::serial_put_string(out);
-        Ok(())
+        // a broken console shouldn't take the caller down, least of
+        // all the panic handler. print! drops this
+        HAL::serial_put_string(out).map_err(|_| Error)
+    }
+}
+
+/// What the panic handler prints through instead. There's no lock
+/// here, and the ones under it get broken rather than waited on, so a
+/// panic while someone is printing (this hart included) still gets
+/// its message out. Use panic_println!.
+pub struct PanicPass;
+
+impl Write for PanicPass {
+    fn write_str(&mut self, out: &str) -> Result<(), Error> {
+        HAL::serial_panic_put_string(out).map_err(|_| Error)
     }
 }
 
@@ -46,6 +61,19 @@ macro_rules! println
     });
 }
 
+/// println!, but for the panic handler, see PanicPass
+macro_rules! panic_println
+{
+    ($fmt:expr) => ({
+        use ::core::fmt::Write;
+        let _ = write!(crate::log::PanicPass, concat!($fmt, "\r\n"));
+    });
+    ($fmt:expr, $($args:tt)+) => ({
+        use ::core::fmt::Write;
+        let _ = write!(crate::log::PanicPass, concat!($fmt, "\r\n"), $($args)+);
+    });
+}
+
 pub enum LogSeverity {
     Debug,
     Info,
@@ -15,7 +15,6 @@
 #![allow(dead_code)]
 
 use core::cell::OnceCell;
-use core::mem::MaybeUninit;
 use core::panic::PanicInfo;
 extern crate alloc;
 
@@ -33,6 +32,9 @@ pub mod file;
 pub mod hal;
 pub mod id;
 pub mod wasm;
+pub mod time;
+pub mod backtrace;
+pub mod hartlocal;
 
 pub static BANNER: &str = r#"
 Mellow Swirled,
@@ -44,11 +46,8 @@ Mellow Swirled,
 
 "#;
 
-use crate::lock::condition::ConditionVar;
 use crate::hal::*;
 
-// sync init accross harts
-static mut GLOBAL_INIT_FLAG: MaybeUninit<ConditionVar> = MaybeUninit::uninit();
 // pass the initial kernel page table to non-zero id harts. This is
 // not how it is accessed after inialization
 static mut KERNEL_PAGE_TABLE: OnceCell<PageTable> = OnceCell::new();
@@ -56,20 +55,23 @@ static mut KERNEL_PAGE_TABLE: OnceCell<PageTable> = OnceCell::new();
 // The never type "!" means diverging function (never returns).
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    let default = format_args!("No message provided");
-    let msg = match info.message() {
-        Some(msg) => msg,
-        None => &default,
-    };
+    // before anything that might not work, like printing
+    HAL::failstate_write(info);
+    // don't let the other harts keep going in a broken kernel
+    HAL::halt_others();
+    let msg = info.message();
     match info.location() {
         None => {
-            println!("PANIC! {} at {}", msg, "No location provided");
+            panic_println!("PANIC! {} at {}", msg, "No location provided");
         }
         Some(loc) => {
-            println!("PANIC! {} at {}:{}", msg, loc.file(), loc.line());
+            panic_println!("PANIC! {} at {}:{}", msg, loc.file(), loc.line());
         }
     }
-    loop {}
+    backtrace::print_here();
+    // a non-zero code, so whoever started us can tell this apart from
+    // a clean shutdown
+    HAL::shutdown(1)
 }
 
 // Primary kernel bootstrap function.
@@ -98,6 +100,7 @@ pub extern "C" fn main() -> ! {
         }
     }
     log!(Info, "Initialized the kernel page table...");
+    time::init();
     log!(Info, "Finished plic globl init...");
     unsafe {
         log!(Debug, "Testing page allocation and freeing...");
@@ -120,16 +123,23 @@ pub extern "C" fn main() -> ! {
     log!(Debug, "Successfuly initialized the process system...");
     log!(Info, "Completed all hart0 initialization and testing...");
 
-    unsafe {
-        // release the waiting harts
-        GLOBAL_INIT_FLAG.assume_init_mut().update(1);
-        log!(Error, "Do CPU discovery and setup with HAL::wake_one");
+    // Bring up the other harts. They have nothing to do yet, so they
+    // will wait in their schedulers
+    for _ in 1..HAL::nhart() {
+        match HAL::wake_one(secondary_hart) {
+            Ok(()) => {},
+            Err(HALCPUError::OutOfCPU) => break,
+            Err(HALCPUError::WakeFailed) => {
+                log!(Warning, "Failed to wake a hart, continuing without it");
+            },
+        }
     }
 
     hook::test_insert();
     log!(Debug, "Hook testing done");
     wasm::test_wasm();
-    panic!("got as far as I wanted?");
+    log!(Info, "Got as far as I wanted, shutting down");
+    HAL::shutdown(0);
 
     // we want to test multiple processes with multiple harts
     // process::test_multiprocess_syscall();
@@ -137,6 +147,15 @@ pub extern "C" fn main() -> ! {
     panic!("Reached the end of kernel main! Did the root process not start?");
 }
 
+/// Where harts other than the boot hart start, once the HAL has set
+/// them up
+fn secondary_hart() -> ! {
+    unsafe {
+        vm::local_init(KERNEL_PAGE_TABLE.get().expect("Woke a hart before the kernel page table was made!"));
+    }
+    log!(Info, "Hart online, entering the scheduler...");
+    process::scheduler_loop()
+}
 
 // -------------------------------------------------------------------
 //
@@ -5,6 +5,7 @@ use core::mem::{size_of, MaybeUninit};
 use core::ptr::copy_nonoverlapping;
 use core::cell::OnceCell;
 use core::cell::LazyCell;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use crate::hal::*;
 use crate::vm::VmError;
@@ -12,10 +13,11 @@ use crate::vm::{request_phys_page, PhysPageExtent};
 use crate::file::elf64::*;
 use crate::lock::mutex::Mutex;
 use crate::id::IdGenerator;
+use crate::hartlocal::this_hart;
 
 
 mod scheduler;
-use crate::process::scheduler::ProcessQueue;
+pub use scheduler::ProcessQueue;
 
 
 static mut PID_COUNTER: LazyCell<Mutex<IdGenerator>> = LazyCell::new(|| Mutex::new(IdGenerator::new()));
@@ -31,9 +33,16 @@ pub use syscall::scall_rust_standard;
 // TODO LazyCell? unclear
 static mut QUEUE: OnceCell<Mutex<ProcessQueue>> = OnceCell::new();
 
+/// Set when serial input arrives, see scheduler_loop
+static INPUT_READY: AtomicBool = AtomicBool::new(false);
+
+/// Longest an idle hart sleeps. Nothing wakes it when another hart
+/// queues a process, so it has to come back and look
+const IDLE_SLEEP_NANOS: u64 = 10_000_000;
+
 
 /// Global init for all process related stuff. Not exaustive, also
-/// need hartlocal_info_interrupt_stack_init
+/// needs HAL::switch_setup
 pub fn init_process_structure() {
     unsafe {
         match QUEUE.set(Mutex::new(ProcessQueue::new())) {
@@ -43,13 +52,19 @@ pub fn init_process_structure() {
             },
         }
     }
+    HAL::serial_on_input(input_arrived);
+}
+
+/// Serial input callback, from an interrupt handler
+fn input_arrived() {
+    INPUT_READY.store(true, Ordering::Release);
 }
 
 // use hart local info to get the currently running process
 //
 // this is a *MOVE* of the process. Handle elsewhere
 fn get_running_process() -> Process {
-    HAL::restore_gp_info().current_process
+    HAL::restore_process()
 }
 
 #[derive(Debug)]
@@ -73,6 +88,7 @@ pub struct Process {
     id: usize,                  // uninit with 0
     state: ProcessState,        // use uninit state
     pgtbl: PageTable,                     // uninizalied with null
+    asid: Asid,                 // unassigned until first switched to
     phys_pages: MaybeUninit<VecDeque<PhysPageExtent>>, // vec to avoid Ord requirement
     // ^ hopefully it's clear how this is uninit
     // TODO consider this as a OnceCell or LazyCell
@@ -87,6 +103,8 @@ pub struct Process {
 #[derive(Debug)]
 pub enum ProcError {
     OOM,
+    /// A process address that isn't mapped the way it needs to be
+    BadAddress,
 }
 
 fn user_process_flags(r: bool, w: bool, e: bool) -> PageMapFlags {
@@ -114,6 +132,7 @@ impl Process {
                 Ok(p) => p,
                 Err(_) => return Err(ProcError::OOM),
             },
+            asid: Asid::new(),
             phys_pages: MaybeUninit::uninit(),
             saved_pc: 0,
             saved_sp: 0,
@@ -121,12 +140,18 @@ impl Process {
         Ok(out)
     }
 
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
     pub fn initialize64(&mut self, elf: &ELFProgram) -> Result<(), ELFError> {
         // Doesn't assert uninitialized state so you can do a write over of an existing process
 
         match self.state {
             ProcessState::Uninitialized => {
                 self.id = unsafe {PID_COUNTER.lock().generate()};
+                // the one from new_uninit, which would otherwise leak
+                HAL::pgtbl_free(self.pgtbl);
                 self.pgtbl = match HAL::pgtbl_new_empty() {
                     Ok(p) => p,
                     Err(_) => return Err(ELFError::FailedAlloc),
@@ -184,25 +209,28 @@ impl Process {
                 kernel_process_flags(true, true, false),
             )?;
 
-            // This maps hart 0, 1 stack pages in opposite order as entry.S. Shouln't necessarily be a
-            // problem.
-            let base = HAL::stacks_start();
-            for s in 0..HAL::NHART {
-                let stack = unsafe { base.byte_add(PAGE_SIZE * (1 + s * 3)) };
+            // Each hart id gets an equal slice of the stack area, counted down
+            // from the top like entry.S does, with a guard page at the bottom.
+            // Hart ids need not be dense, so map every slot.
+            let top = HAL::stacks_end();
+            let stack_and_guard_page_num = (HAL::stacks_end() as usize - HAL::stacks_start() as usize) /
+                (HAL::MAX_HARTS * PAGE_SIZE);
+            for s in 0..HAL::MAX_HARTS {
+                let stack = unsafe { top.byte_sub(PAGE_SIZE * ((s + 1) * stack_and_guard_page_num - 1)) };
                 HAL::pgtbl_insert_range(
                     self.pgtbl,
                     stack,
                     stack,
-                    PAGE_SIZE * 2,
+                    PAGE_SIZE * (stack_and_guard_page_num - 1),
                     kernel_process_flags(true, true, false),
                 )?;
             }
 
-            // This maps hart 0, 1 stack pages in opposite order as entry.S. Shouln't necessarily be a
-            // problem.
-            let base = HAL::intstacks_start();
-            for i in 0..HAL::NHART {
-                let m_intstack = unsafe { base.byte_add(PAGE_SIZE * (1 + i * 4)) };
+            // Same for the interrupt stacks, 4 pages each: guard, m-mode,
+            // guard, s-mode from low to high
+            let top = HAL::intstacks_end();
+            for i in 0..HAL::MAX_HARTS {
+                let m_intstack = unsafe { top.byte_sub(PAGE_SIZE * ((i + 1) * 4 - 1)) };
                 // Map hart i m-mode handler.
                 HAL::pgtbl_insert_range(
                     self.pgtbl,
@@ -340,18 +368,11 @@ impl Process {
     /// This is a (kind of) context switch
     ///
     /// This consumes the process from the rust perspective, but it is
-    /// actually preserved elsewhere (gp info) and restored. This is
-    /// because we need to preserve info across entering and exiting
-    /// the process, but no non-global rust location does that, and we
-    /// can't use a global array or anything like that because we need
-    /// to have each hart's process's lifetime be independent, and
-    /// further, it doesn't make sense to have Process be Sync when it
-    /// is not.
-    ///
-    /// TODO consider if there is a non-gp solution involing global
-    /// pointers to heap allocated locations per hart. That is
-    /// conceptually what is going on, but I still think we would have
-    /// Sync/Send issues
+    /// actually preserved elsewhere (this hart's HartLocal) and
+    /// restored. This is because we need to preserve info across
+    /// entering and exiting the process, but no non-global rust
+    /// location does that, and each hart's process's lifetime needs
+    /// to be independent of the others.
     pub fn start(mut self) -> ! {
         match self.state {
             ProcessState::Unstarted => {},
@@ -359,13 +380,12 @@ impl Process {
         }
         self.state = ProcessState::Running;
 
-        extern "C" {pub fn process_start_asm(pc: usize, pgtbl: usize, sp: usize) -> !;}
+        extern "C" {pub fn process_start_asm(pc: usize, satp: usize, sp: usize) -> !;}
 
         let saved_pc = self.saved_pc;
-        let pgtbl_base = self.pgtbl.addr as usize;
+        let satp = HAL::pgtbl_prepare_switch(&self.pgtbl, &mut self.asid);
         let saved_sp = self.saved_sp;
-        let gpi = <HAL as HALSwitch>::GPInfo::new(self);
-        HAL::save_gp_info(gpi);
+        HAL::save_process(self);
 
         unsafe {
             // we can't use PageTable.write_satp here becuase this is
@@ -373,7 +393,7 @@ impl Process {
             // be. We want to do that later in the asm.
             //
             // relies on args in a0, a1, a2 in order (see extern C)
-            process_start_asm(saved_pc, pgtbl_base, saved_sp);
+            process_start_asm(saved_pc, satp, saved_sp);
         }
     }
 
@@ -390,16 +410,55 @@ impl Process {
         }
         self.state = ProcessState::Running;
 
-        extern "C" {pub fn process_resume_asm(pc: usize, pgtbl: usize, sp: usize) -> !;}
+        extern "C" {pub fn process_resume_asm(pc: usize, satp: usize, sp: usize) -> !;}
 
         let saved_pc = self.saved_pc;
-        let pgtbl_base = self.pgtbl.addr as usize;
+        let satp = HAL::pgtbl_prepare_switch(&self.pgtbl, &mut self.asid);
         let saved_sp = self.saved_sp;
-        let gpi = <HAL as HALSwitch>::GPInfo::new(self);
-        HAL::save_gp_info(gpi);
+        HAL::save_process(self);
 
         unsafe {
-            process_resume_asm(saved_pc, pgtbl_base, saved_sp);
+            process_resume_asm(saved_pc, satp, saved_sp);
+        }
+    }
+}
+
+impl Process {
+    /// Copy bytes into this process's memory at dest, which must be
+    /// mapped user writable all the way through. Nothing is written
+    /// unless all of it is.
+    fn copy_to_user(&self, dest: usize, bytes: &[u8]) -> Result<(), ProcError> {
+        let end = dest.checked_add(bytes.len()).ok_or(ProcError::BadAddress)?;
+        // check every page first, so a bad one doesn't leave a
+        // partial write behind
+        let mut virt = dest;
+        while virt < end {
+            self.user_writable(virt)?;
+            virt = (virt & !(PAGE_SIZE - 1)) + PAGE_SIZE;
+        }
+
+        let mut done = 0;
+        while done < bytes.len() {
+            let virt = dest + done;
+            let phys = self.user_writable(virt)?;
+            // up to the end of this page
+            let len = (bytes.len() - done).min(PAGE_SIZE - virt % PAGE_SIZE);
+            unsafe {
+                // process memory is all in the kernel's identity
+                // mapping, so we can go through the physical address
+                copy_nonoverlapping(bytes[done..].as_ptr(), phys as *mut u8, len);
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn user_writable(&self, virt: usize) -> Result<usize, ProcError> {
+        match HAL::pgtbl_lookup(&self.pgtbl, virt as VirtAddress) {
+            Some((phys, flags)) if flags.contains(PageMapFlags::User | PageMapFlags::Write) => {
+                Ok(phys as usize)
+            },
+            _ => Err(ProcError::BadAddress),
         }
     }
 }
@@ -455,6 +514,19 @@ fn process_pause(pc: usize, sp: usize, cause: usize) -> ! {
     }
 }
 
+/// Go straight back to the process that made a syscall, with ret as
+/// the result. pc and sp are as passed to scall_rust_standard.
+fn process_return(mut proc: Process, pc: usize, sp: usize, ret: isize) -> ! {
+    proc.saved_pc = pc + 4;
+    // ^ ecall doesn't automatically increment pc
+    proc.saved_sp = sp;
+    if proc.copy_to_user(sp + HAL::SCALL_RETURN_OFFSET, &ret.to_ne_bytes()).is_err() {
+        panic!("Process {} has nowhere to put a syscall result!", proc.id);
+    }
+    proc.state = ProcessState::Ready;
+    proc.resume()
+}
+
 #[no_mangle]
 pub extern "C" fn process_exit_rust(exit_code: isize) -> ! {
     let proc = get_running_process();
@@ -463,17 +535,56 @@ pub extern "C" fn process_exit_rust(exit_code: isize) -> ! {
     // ^ ensure that the never returning scheduler call doesn't extend
     // the life of the process
 
-    // This is careful code to avoid holding the lock when we enter
-    // the process, as that would lead to an infinite lock
-    let next;
-    unsafe {
-        let mut locked = QUEUE.get().unwrap().lock();
-        next = locked.get_ready_process();
-    }
-    match next.state {
-        ProcessState::Ready => {next.resume()},
-        ProcessState::Unstarted => {next.start()},
-        _ => {panic!("Bad process state from scheduler!")}
+    scheduler_loop()
+}
+
+/// Called by the HAL when the running process faulted (bad memory
+/// access, illegal instruction and so on). Runs on the kernel stack
+/// and page table, like process_exit_rust.
+pub extern "C" fn process_fault_rust() -> ! {
+    let mut proc = get_running_process();
+    log!(Warning, "Killing process {} after a fault.", proc.id);
+    proc.state = ProcessState::Dead;
+    drop(proc);
+
+    scheduler_loop()
+}
+
+/// The per hart scheduler. Runs whatever is ready from this hart's
+/// queue, then the shared one, and waits for something to become
+/// ready if nothing is. Every hart ends up here once it has nothing
+/// else to do.
+pub fn scheduler_loop() -> ! {
+    loop {
+        // This is careful code to avoid holding the lock when we enter
+        // the process, as that would lead to an infinite lock
+        let mut next = this_hart().run_queue.borrow_mut().try_get_ready_process();
+        if next.is_none() {
+            unsafe {
+                let mut locked = QUEUE.get().unwrap().lock();
+                next = locked.try_get_ready_process();
+            }
+        }
+        match next {
+            Some(p) => match p.state {
+                ProcessState::Ready => {p.resume()},
+                ProcessState::Unstarted => {p.start()},
+                _ => {panic!("Bad process state from scheduler!")}
+            },
+            None => {
+                // We have interrupts off here, so look for IPIs and
+                // input ourselves, then sleep until an interrupt
+                // (input, an IPI or the timer) might have changed
+                // things. Input that came in since the last look
+                // means we go around again instead.
+                HAL::ipi_poll();
+                let _ = HAL::serial_poll();
+                if !INPUT_READY.swap(false, Ordering::AcqRel) {
+                    HAL::timer_set(HAL::nanos_to_ticks(IDLE_SLEEP_NANOS));
+                    HAL::wait_for_interrupt();
+                }
+            }
+        }
     }
 }
 
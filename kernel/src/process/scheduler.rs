@@ -18,7 +18,7 @@ pub struct ProcessQueue {
 }
 
 impl ProcessQueue {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
             proc_queue: VecDeque::new()
         }
@@ -27,12 +27,27 @@ impl ProcessQueue {
     /// This is the acquiring half of the scheduler. This function
     /// internally enforces fairness and efficiency and everything else
     pub fn get_ready_process(&mut self) -> Process {
+        match self.try_get_ready_process() {
+            Some(p) => p,
+            None => {
+                // TODO This need to communicate with other harts to make sure
+                // it's not just that the other harts are running / own
+                // everything currently
+                panic!("Scheduling queue is empty! The root process died?");
+            }
+        }
+    }
+
+    /// As get_ready_process, but returns None instead of panicing
+    /// when there is nothing to run. The other harts may still own
+    /// processes that will come back.
+    pub fn try_get_ready_process(&mut self) -> Option<Process> {
         // iterate while the queue is non-empty
         while let Some(head) = self.proc_queue.pop_front() {
             match head.state {
                 // found something we can run
                 ProcessState::Ready | ProcessState::Unstarted => {
-                    return head
+                    return Some(head)
                 },
 
                 // found something we might be able to run, check. If
@@ -60,11 +75,7 @@ impl ProcessQueue {
             }
         }
         // The queue must be empty, there is nothing to run
-
-        // TODO This need to communicate with other harts to make sure
-        // it's not just that the other harts are running / own
-        // everything currently
-        panic!("Scheduling queue is empty! The root process died?");
+        None
     }
 
     /// This is for returning a process that has just stopped running but
@@ -83,3 +94,8 @@ impl ProcessQueue {
     }
 }
 
+impl Default for ProcessQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
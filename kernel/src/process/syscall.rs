@@ -2,6 +2,7 @@
 /// syscall.s for the asm half of this
 
 use super::*;
+use crate::time::Clock;
 
 /// System call rust handler. This is called from hal after scall_asm. See there
 /// for calling convention info.
@@ -22,8 +23,20 @@ pub fn scall_rust_standard(a0: usize, a1: usize, a2: usize, a3: usize,
             // see the comment on scall_direct for why we have these
             process_pause(pc, sp, 0); // cause 0, explicit yield
         }
+        CLOCK_GETTIME => {
+            let proc = get_running_process();
+            let ret = clock_gettime(&proc, a0, a1);
+            process_return(proc, pc, sp, ret);
+        }
+        GETTIMEOFDAY => {
+            let proc = get_running_process();
+            let ret = gettimeofday(&proc, a0, a1);
+            process_return(proc, pc, sp, ret);
+        }
         _ => {
-            panic!("Uncaught system call: {}", a7);
+            // a bad program shouldn't take the kernel down with it
+            log!(Warning, "Uncaught system call: {}", a7);
+            process_fault_rust()
         }
     }
 }
@@ -54,19 +67,63 @@ pub fn scall_rust_standard(a0: usize, a1: usize, a2: usize, a3: usize,
 pub extern "C" fn scall_direct(a0: usize, a1: usize, a2: usize, a3: usize,
                                a4: usize, a5: usize, a6: usize, a7: usize)
                                -> usize {
-    match a7 {
-        SCHED_YIELD => {
-            1
-        },
-        _ => {
-            0
-        }
-    }
+    // Everything runs on the kernel side for now. The time calls
+    // write to process memory through the process page table, yield
+    // leaves the process, and an unknown call kills it
+    1
 }
 
 /// Default handler for syscalls that aren't yet implemented
 
 
+// -------------------------------------------------------------------
+//
+// Handlers. These return what goes back in a0, which is negative
+// errno on failure like linux.
+//
+
+const EFAULT: isize = 14;
+const EINVAL: isize = 22;
+
+/// timespec and timeval are both a pair of 64 bit numbers, seconds
+/// and then a fraction of one
+fn put_time_pair(proc: &Process, dest: usize, secs: u64, frac: u64) -> isize {
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&(secs as i64).to_ne_bytes());
+    bytes[8..].copy_from_slice(&(frac as i64).to_ne_bytes());
+    match proc.copy_to_user(dest, &bytes) {
+        Ok(()) => 0,
+        Err(_) => -EFAULT,
+    }
+}
+
+fn clock_gettime(proc: &Process, clock_id: usize, tp: usize) -> isize {
+    let clock = match Clock::from_linux_id(clock_id) {
+        Some(c) => c,
+        None => return -EINVAL,
+    };
+    let now = clock.now();
+    put_time_pair(proc, tp, now.as_secs(), now.subsec_nanos() as u64)
+}
+
+fn gettimeofday(proc: &Process, tv: usize, tz: usize) -> isize {
+    if tv != 0 {
+        let now = Clock::Realtime.now();
+        let ret = put_time_pair(proc, tv, now.as_secs(), now.subsec_micros() as u64);
+        if ret != 0 {
+            return ret;
+        }
+    }
+    if tz != 0 {
+        // always UTC, no DST
+        if proc.copy_to_user(tz, &[0; 8]).is_err() {
+            return -EFAULT;
+        }
+    }
+    0
+}
+
+
 // -------------------------------------------------------------------
 //
 // Just a lot of constants down here.
@@ -0,0 +1,69 @@
+/// This module holds the kernel's clocks, for the kernel and for the
+/// clock syscalls.
+///
+/// CLOCK_MONOTONIC is the HAL timer's uptime. CLOCK_REALTIME is that
+/// plus when we booted, worked out once from the hardware wall clock
+/// in init, so the two never drift apart. Without a wall clock
+/// CLOCK_REALTIME starts at the epoch.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::hal::*;
+
+/// Wall clock time at which the monotonic clock was 0, in
+/// nanoseconds since the epoch. Good until 2554.
+static BOOT_EPOCH_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// The clocks we have
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Clock {
+    /// Time since the unix epoch
+    Realtime,
+    /// Time since boot, never goes backwards
+    Monotonic,
+}
+
+impl Clock {
+    /// The clock for a linux clockid_t. The coarse and raw variants
+    /// are no different here, and we never suspend, so boot time is
+    /// just monotonic.
+    pub fn from_linux_id(id: usize) -> Option<Self> {
+        match id {
+            0 | 5 => Some(Clock::Realtime),          // REALTIME(_COARSE)
+            1 | 4 | 6 | 7 => Some(Clock::Monotonic), // MONOTONIC(_RAW, _COARSE), BOOTTIME
+            _ => None,
+        }
+    }
+
+    pub fn now(self) -> Duration {
+        match self {
+            Clock::Realtime => realtime(),
+            Clock::Monotonic => monotonic(),
+        }
+    }
+}
+
+/// Read the wall clock to anchor CLOCK_REALTIME. Call once after the
+/// HAL is set up and the kernel page table is in, as the clock may
+/// be a device.
+pub fn init() {
+    match HAL::wall_clock() {
+        Some(wall) => {
+            let boot = wall.saturating_sub(HAL::uptime());
+            BOOT_EPOCH_NANOS.store(boot.as_nanos() as u64, Ordering::Release);
+            log!(Info, "Wall clock reads {}s since the epoch", wall.as_secs());
+        },
+        None => {
+            log!(Warning, "No wall clock, CLOCK_REALTIME will count from the epoch");
+        },
+    }
+}
+
+pub fn monotonic() -> Duration {
+    HAL::uptime()
+}
+
+pub fn realtime() -> Duration {
+    Duration::from_nanos(BOOT_EPOCH_NANOS.load(Ordering::Acquire)) + HAL::uptime()
+}
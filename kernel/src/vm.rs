@@ -69,7 +69,7 @@ pub enum VmError {
 /// TODO better error type
 pub fn global_init() -> Result<PageTable, ()> {
     unsafe {
-        match PAGEPOOL.set(PagePool::new(HAL::bss_end(), HAL::memory_end())) {
+        match PAGEPOOL.set(PagePool::new(HAL::heap_start(), HAL::memory_end())) {
             Ok(_) => {}
             Err(_) => {
                 panic!("vm double init.")
@@ -153,13 +153,14 @@ pub fn kpage_init() -> Result<PageTable, VmError> {
         )?;
         // log!(Debug, "Succesfully mapped kernel data into kernel pgtable...");
 
-        // This maps hart 0, 1 stack pages in opposite order as entry.S. Shouln't necessarily be a
-        // problem.
-        let base = HAL::stacks_start();
-        let stack_and_guard_page_num = ((HAL::stacks_end() as usize - HAL::stacks_start() as usize) /
-                          (HAL::NHART * PAGE_SIZE));
-        for s in 0..HAL::NHART {
-            let stack = unsafe { base.byte_add(PAGE_SIZE * (1 + s * stack_and_guard_page_num)) };
+        // Each hart id gets an equal slice of the stack area, counted down
+        // from the top like entry.S does, with a guard page at the bottom.
+        // Hart ids need not be dense, so map every slot.
+        let top = HAL::stacks_end();
+        let stack_and_guard_page_num = (HAL::stacks_end() as usize - HAL::stacks_start() as usize) /
+                          (HAL::MAX_HARTS * PAGE_SIZE);
+        for s in 0..HAL::MAX_HARTS {
+            let stack = unsafe { top.byte_sub(PAGE_SIZE * ((s + 1) * stack_and_guard_page_num - 1)) };
             HAL::pgtbl_insert_range(
                 kpage_table,
                 stack,
@@ -174,11 +175,11 @@ pub fn kpage_init() -> Result<PageTable, VmError> {
             // );
         }
 
-        // This maps hart 0, 1 stack pages in opposite order as entry.S. Shouln't necessarily be a
-        // problem.
-        let base = HAL::intstacks_start();
-        for i in 0..HAL::NHART {
-            let m_intstack = unsafe { base.byte_add(PAGE_SIZE * (1 + i * 4)) };
+        // Same for the interrupt stacks, 4 pages each: guard, m-mode,
+        // guard, s-mode from low to high
+        let top = HAL::intstacks_end();
+        for i in 0..HAL::MAX_HARTS {
+            let m_intstack = unsafe { top.byte_sub(PAGE_SIZE * ((i + 1) * 4 - 1)) };
             // Map hart i m-mode handler.
             HAL::pgtbl_insert_range(
                 kpage_table,
@@ -214,21 +215,21 @@ pub fn kpage_init() -> Result<PageTable, VmError> {
 
         HAL::pgtbl_insert_range(
             kpage_table,
-            HAL::bss_end(),
-            HAL::bss_end(),
-            HAL::memory_end().addr() - HAL::bss_end().addr(),
+            HAL::heap_start(),
+            HAL::heap_start(),
+            HAL::memory_end().addr() - HAL::heap_start().addr(),
             PageMapFlags::Read | PageMapFlags::Write
         )?;
         // log!(Debug, "Succesfully mapped kernel heap...");
 
         // finished all generic mappings, now do hardware mappings
         let to_map = HAL::kernel_reserved_areas();
-        for (area, flags) in to_map {
+        for (start, num, flags) in to_map {
             HAL::pgtbl_insert_range(
                 kpage_table,
-                area.start(),
-                area.end(),
-                PAGE_SIZE * area.num,
+                start as *mut usize,
+                start as *mut usize,
+                PAGE_SIZE * num,
                 flags
             )?;
         }
@@ -248,6 +249,9 @@ pub fn kpage_init() -> Result<PageTable, VmError> {
                 },
                 HALVMError::UnsupportedFlags(mask) => {
                     panic!("Unsupported flags in kernel mapping: {mask:x}!");
+                },
+                HALVMError::NotMapped => {
+                    panic!("Kernel mapping changed something that isn't mapped?!");
                 }
             }
         },